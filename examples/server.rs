@@ -36,12 +36,12 @@ fn load_certs(path: &Path) -> io::Result<Vec<CertificateDer<'static>>> {
 }
 
 fn load_key(path: &Path) -> io::Result<PrivateKeyDer<'static>> {
-    Ok(private_key(&mut BufReader::new(File::open(path)?))
+    private_key(&mut BufReader::new(File::open(path)?))
         .unwrap()
         .ok_or(io::Error::new(
             ErrorKind::Other,
             "no private key found".to_string(),
-        ))?)
+        ))
 }
 
 #[tokio::main]
@@ -0,0 +1,50 @@
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::{client, server, TlsStream};
+
+/// Future returned from `TlsStream::data_available`.
+pub struct DataAvailable<'a, S> {
+    stream: &'a mut S,
+}
+
+impl<'a, S> DataAvailable<'a, S> {
+    #[inline]
+    pub(crate) fn new(stream: &'a mut S) -> Self {
+        DataAvailable { stream }
+    }
+}
+
+impl<'a, IO: AsyncRead + AsyncWrite + Unpin> Future for DataAvailable<'a, client::TlsStream<IO>> {
+    type Output = io::Result<()>;
+
+    #[inline]
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.get_mut().stream.poll_data_available(cx)
+    }
+}
+
+impl<'a, IO: AsyncRead + AsyncWrite + Unpin> Future for DataAvailable<'a, server::TlsStream<IO>> {
+    type Output = io::Result<()>;
+
+    #[inline]
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.get_mut().stream.poll_data_available(cx)
+    }
+}
+
+impl<'a, IO: AsyncRead + AsyncWrite + Unpin> Future for DataAvailable<'a, TlsStream<IO>> {
+    type Output = io::Result<()>;
+
+    #[inline]
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        match self.get_mut().stream {
+            TlsStream::Client(stream) => stream.poll_data_available(cx),
+            TlsStream::Server(stream) => stream.poll_data_available(cx),
+        }
+    }
+}
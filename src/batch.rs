@@ -0,0 +1,76 @@
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use tokio::io::AsyncWrite;
+
+/// The buffer a `TlsStream::batch` closure writes into.
+///
+/// Unlike a bare `Vec<u8>`, this implements only `std::io::Write`, so its methods do
+/// not clash with `AsyncWriteExt`.
+#[derive(Debug, Default)]
+pub struct BatchWriter(Vec<u8>);
+
+impl io::Write for BatchWriter {
+    #[inline]
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    #[inline]
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.0.extend_from_slice(buf);
+        Ok(())
+    }
+
+    #[inline]
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Future returned from `TlsStream::batch`.
+pub struct Batch<'a, W, T> {
+    dst: &'a mut W,
+    buf: Vec<u8>,
+    pos: usize,
+    result: Option<io::Result<T>>,
+}
+
+impl<'a, W, T> Batch<'a, W, T> {
+    pub(crate) fn new(dst: &'a mut W, f: impl FnOnce(&mut BatchWriter) -> io::Result<T>) -> Self {
+        let mut buf = BatchWriter::default();
+        let result = f(&mut buf);
+        Batch {
+            dst,
+            buf: buf.0,
+            pos: 0,
+            result: Some(result),
+        }
+    }
+}
+
+impl<'a, W, T> Future for Batch<'a, W, T>
+where
+    W: AsyncWrite + Unpin,
+    T: Unpin,
+{
+    type Output = io::Result<T>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+
+        while this.pos < this.buf.len() {
+            let n = ready!(Pin::new(&mut *this.dst).poll_write(cx, &this.buf[this.pos..]))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            this.pos += n;
+        }
+
+        ready!(Pin::new(&mut *this.dst).poll_flush(cx))?;
+        Poll::Ready(this.result.take().expect("polled after completion"))
+    }
+}
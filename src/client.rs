@@ -1,17 +1,28 @@
 use std::io;
+use std::mem;
+use std::num::NonZeroU64;
 #[cfg(unix)]
 use std::os::unix::io::{AsRawFd, RawFd};
 #[cfg(windows)]
 use std::os::windows::io::{AsRawSocket, RawSocket};
 use std::pin::Pin;
-#[cfg(feature = "early-data")]
-use std::task::Waker;
+use std::sync::Arc;
 use std::task::{Context, Poll};
+use std::time::{Instant, SystemTime};
 
+use rustls::crypto::CryptoProvider;
 use rustls::ClientConnection;
 use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
 
-use crate::common::{IoSession, Stream, TlsState};
+use crate::common::{self, next_connection_id, IoSession, Stream, TlsState};
+use crate::deadline::HandshakeDeadline;
+use crate::key_update::KeyUpdate;
+use crate::rate_limit::ReadRateLimit;
+use crate::{
+    Batch, BatchWriter, CopyWithTee, DataAvailable, Downgrade, HandshakeInterrupted,
+    HandshakePhase, HandshakeProfile, HandshakeSummary, HttpVersion, KeepTrailingData,
+    KeyUpdateHandle, OnEstablished,
+};
 
 /// A wrapper around an underlying raw stream which implements the TLS or SSL
 /// protocol.
@@ -20,9 +31,38 @@ pub struct TlsStream<IO> {
     pub(crate) io: IO,
     pub(crate) session: ClientConnection,
     pub(crate) state: TlsState,
+    pub(crate) handshake_completed_at: Option<Instant>,
+    pub(crate) read_rate_limit: Option<Box<ReadRateLimit>>,
+    pub(crate) read_batch_limit: usize,
+    pub(crate) connection_id: u64,
+    pub(crate) key_update: KeyUpdate,
+    pub(crate) handshake_deadline: HandshakeDeadline,
+    pub(crate) on_established: OnEstablished,
+    pub(crate) crypto_provider: Option<Arc<CryptoProvider>>,
+    #[cfg(feature = "early-data")]
+    pub(crate) flush_completes_handshake: bool,
 }
 
 impl<IO> TlsStream<IO> {
+    /// A stream with every setting at its default, which the connector then adjusts.
+    pub(crate) fn new(io: IO, session: ClientConnection, state: TlsState) -> Self {
+        TlsStream {
+            io,
+            session,
+            state,
+            handshake_completed_at: None,
+            read_rate_limit: None,
+            read_batch_limit: 1,
+            connection_id: next_connection_id(),
+            key_update: KeyUpdate::default(),
+            handshake_deadline: HandshakeDeadline::default(),
+            on_established: OnEstablished::default(),
+            crypto_provider: None,
+            #[cfg(feature = "early-data")]
+            flush_completes_handshake: true,
+        }
+    }
+
     #[inline]
     pub fn get_ref(&self) -> (&IO, &ClientConnection) {
         (&self.io, &self.session)
@@ -37,6 +77,331 @@ impl<IO> TlsStream<IO> {
     pub fn into_inner(self) -> (IO, ClientConnection) {
         (self.io, self.session)
     }
+
+    /// The bytes that would be lost if the stream were taken apart now, as the
+    /// decrypted plaintext not read yet and the TLS data not written to the IO yet.
+    ///
+    /// Received data is decrypted first, so complete records count as plaintext.
+    /// A record received only in part stays internal to rustls and is left out.
+    ///
+    /// Once the handshake is complete, the TLS data counts against the session's buffer
+    /// limit, so with a limit of `limit` a write of up to `limit - buffered_bytes()?.1`
+    /// bytes is accepted in full. rustls does not report the limit, so it is the
+    /// caller's to remember.
+    ///
+    /// Decrypting is the session's processing of new packets, as done on a read. A
+    /// record that fails to decrypt is reported as an `InvalidData` error, and the
+    /// session is left as failed.
+    #[inline]
+    pub fn buffered_bytes(&mut self) -> io::Result<(usize, usize)> {
+        common::buffered_bytes(&mut self.session)
+    }
+
+    /// Like `into_inner`, but hands the stream back if `buffered_bytes` reports any
+    /// plaintext still to be read or TLS data still to be written, or fails, so that
+    /// a protocol handoff cannot silently drop either.
+    #[allow(clippy::result_large_err)] // the stream is handed back as is
+    pub fn try_into_inner(mut self) -> Result<(IO, ClientConnection), Self> {
+        match self.buffered_bytes() {
+            Ok((0, 0)) => Ok(self.into_inner()),
+            _ => Err(self),
+        }
+    }
+
+    /// An ID for correlating logs, unique among the streams created in this process
+    /// and fixed for the lifetime of the connection.
+    #[inline]
+    pub fn connection_id(&self) -> u64 {
+        self.connection_id
+    }
+
+    /// Returns a handle through which another task can request a TLS 1.3 key update,
+    /// applied at the next write or flush.
+    #[inline]
+    pub fn key_update_handle(&mut self) -> KeyUpdateHandle {
+        self.key_update.handle()
+    }
+
+    /// A summary of the parameters negotiated during the handshake.
+    #[inline]
+    pub fn handshake_summary(&self) -> HandshakeSummary {
+        HandshakeSummary::new(&self.session)
+    }
+
+    /// How the connection was authenticated and whether it resumed an earlier session,
+    /// or `None` while the handshake is in progress.
+    #[inline]
+    pub fn auth_and_resumption(&self) -> Option<HandshakeProfile> {
+        HandshakeProfile::new(&self.session)
+    }
+
+    /// The name of the negotiated key exchange group, such as `"X25519"` or
+    /// `"secp256r1"`, for logging.
+    ///
+    /// The group itself is available from the session's
+    /// `negotiated_key_exchange_group()`.
+    #[inline]
+    pub fn key_exchange_group_name(&self) -> Option<&'static str> {
+        common::key_exchange_group_name(&self.session)
+    }
+
+    /// The HTTP version negotiated through ALPN, if any.
+    ///
+    /// Returns `None` if no protocol was negotiated, or if it is not an HTTP version.
+    #[inline]
+    pub fn negotiated_http_version(&self) -> Option<HttpVersion> {
+        common::negotiated_http_version(&self.session)
+    }
+
+    /// The SHA-256 hash of the server certificate's SubjectPublicKeyInfo, for public key
+    /// pinning.
+    ///
+    /// The hash is computed with the `CryptoProvider` of the connector's `ClientConfig`,
+    /// so it is available whichever provider is in use. Returns `None` before the server
+    /// has sent its certificate, if it cannot be parsed, or if the provider has no
+    /// TLS 1.3 cipher suite using SHA-256.
+    pub fn peer_spki_sha256(&self) -> Option<[u8; 32]> {
+        let cert = self.session.peer_certificates()?.first()?;
+        crate::pinning::spki_sha256(self.crypto_provider.as_ref()?, cert)
+    }
+
+    /// The notBefore and notAfter times of the server certificate, for warning ahead of
+    /// its expiry.
+    ///
+    /// Returns `None` before the server has sent its certificate, or if it cannot be
+    /// parsed.
+    pub fn peer_cert_validity(&self) -> Option<(SystemTime, SystemTime)> {
+        let cert = self.session.peer_certificates()?.first()?;
+        crate::der::validity(cert)
+    }
+
+    /// Whether ALPN negotiated `preferred`, rather than another protocol or none.
+    #[inline]
+    pub fn alpn_was_preferred(&self, preferred: &[u8]) -> bool {
+        self.session.alpn_protocol() == Some(preferred)
+    }
+
+    /// Whether the stream is sending 0-RTT data, that is, early data has been enabled
+    /// and the handshake has not completed yet.
+    ///
+    /// Data written while this is `true` is sent as early data, which may be replayed
+    /// or, if the server rejects it, resent once the handshake completes.
+    #[inline]
+    pub fn is_early_data_phase(&self) -> bool {
+        self.state.is_early_data()
+    }
+
+    /// Whether the read side has reached EOF.
+    ///
+    /// After a read returns no data into a non-empty buffer, this is `true`: either the
+    /// peer sent `close_notify`, or the underlying IO reached EOF.
+    #[inline]
+    pub fn is_eof(&self) -> bool {
+        !self.state.readable()
+    }
+
+    /// Paces reads to at most `limit` bytes per second, or lifts the limit with `None`.
+    ///
+    /// Once the limit is reached, `poll_read` returns `Pending` until a timer fires,
+    /// applying backpressure to the peer. Up to one second's worth of data may be read
+    /// in a burst. Requires a Tokio runtime with the time driver enabled.
+    pub fn set_read_rate_limit(&mut self, limit: Option<NonZeroU64>) {
+        self.read_rate_limit = limit.map(|limit| Box::new(ReadRateLimit::new(limit)));
+    }
+
+    /// How many reads from the underlying IO a single `poll_read` may make; see
+    /// `TlsConnector::read_batch_limit`.
+    #[inline]
+    pub fn set_read_batch_limit(&mut self, limit: usize) {
+        self.read_batch_limit = limit;
+    }
+
+    /// When the handshake completed, or `None` while it is still in progress.
+    #[inline]
+    pub fn handshake_completed_at(&self) -> Option<Instant> {
+        self.handshake_completed_at
+    }
+
+    /// Runs `f` with a summary of the handshake at the moment it completes. With
+    /// 0-RTT, that is once the early data phase has ended, not when `Connect` resolves.
+    ///
+    /// Register it before the handshake, on a `connect_lazy` stream or through
+    /// `Connect::on_established`. If the handshake has already completed, `f` runs
+    /// right away. Only the latest callback is kept, and it runs at most once.
+    pub fn on_established(&mut self, f: impl FnOnce(HandshakeSummary) + Send + Sync + 'static) {
+        let completed = self.handshake_completed_at.is_some();
+        self.on_established
+            .set(Box::new(f), completed, &self.session);
+    }
+
+    /// Whether the session wants to read more TLS data from the transport, as
+    /// `CommonState::wants_read`.
+    #[inline]
+    pub fn wants_read(&self) -> bool {
+        self.session.wants_read()
+    }
+
+    /// Whether the session has TLS data waiting to be written to the transport, as
+    /// `CommonState::wants_write`.
+    #[inline]
+    pub fn wants_write(&self) -> bool {
+        self.session.wants_write()
+    }
+
+    /// A best-effort estimate of the memory held by this connection, in bytes.
+    ///
+    /// Counts the stream itself, the plaintext waiting to be read and the TLS data
+    /// waiting to be written. Buffers internal to rustls, such as the one holding
+    /// partially received records, cannot be observed and are left out.
+    ///
+    /// The buffers are measured as by `buffered_bytes`, which can fail the same way.
+    pub fn memory_usage(&mut self) -> io::Result<usize> {
+        let (plaintext, tls) = self.buffered_bytes()?;
+        Ok(mem::size_of::<Self>() + plaintext + tls)
+    }
+}
+
+impl<IO> TlsStream<IO>
+where
+    IO: AsyncRead + AsyncWrite + Unpin,
+{
+    /// Drives any outstanding handshake to completion, without reading or writing
+    /// application data.
+    ///
+    /// `Connect` already completes the handshake before resolving, unless 0-RTT is in
+    /// use. To interleave the initial handshake with other IO on the same transport,
+    /// poll `Connect` directly and use `Connect::get_mut`.
+    ///
+    /// With 0-RTT, this is where the early data phase ends. Once it returns
+    /// `Ready(Ok(()))`, the session's `is_early_data_accepted()` reports whether the
+    /// server accepted the early data; if it did not, the early data has already been
+    /// sent again as ordinary application data.
+    pub fn poll_handshake(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if self.session.is_handshaking() {
+            self.handshake_deadline.poll_check(cx)?;
+        }
+
+        let mut stream =
+            Stream::new(&mut self.io, &mut self.session).set_eof(!self.state.readable());
+
+        #[cfg(feature = "early-data")]
+        ready!(poll_handle_early_data(
+            &mut self.state,
+            &mut self.handshake_completed_at,
+            &mut self.handshake_deadline,
+            &mut self.on_established,
+            &mut stream,
+            cx,
+            &[]
+        ))?;
+
+        while stream.session.is_handshaking() {
+            ready!(stream.handshake(cx))
+                .map_err(|err| HandshakeInterrupted::wrap(err, handshake_phase(stream.session)))?;
+        }
+        self.handshake_completed_at.get_or_insert_with(Instant::now);
+        self.on_established.fire(stream.session);
+        self.handshake_deadline.clear();
+
+        stream.as_mut_pin().poll_flush(cx)
+    }
+
+    /// Waits until a read would return without waiting: once decrypted application
+    /// data is buffered, or once the peer has closed the connection.
+    ///
+    /// Records that carry no application data, such as session tickets or key
+    /// updates, are processed without completing the future. No plaintext is taken,
+    /// and the read rate limit is not applied.
+    #[inline]
+    pub fn data_available(&mut self) -> DataAvailable<'_, Self> {
+        DataAvailable::new(self)
+    }
+
+    /// Polls for the condition `data_available` waits for.
+    pub fn poll_data_available(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if self.session.is_handshaking() {
+            ready!(self.poll_handshake(cx))?;
+        }
+
+        let mut stream =
+            Stream::new(&mut self.io, &mut self.session).set_eof(!self.state.readable());
+        stream.poll_data_available(cx)
+    }
+
+    /// Runs `f` to write into a buffer, then sends everything it wrote in as few
+    /// records as possible and flushes the stream.
+    ///
+    /// The buffer is sent and flushed even if `f` returns an error, so an early return
+    /// never leaves part of a batch behind. The returned future resolves to the result
+    /// of `f`, or to the error that prevented sending the batch.
+    pub fn batch<F, R>(&mut self, f: F) -> Batch<'_, Self, R>
+    where
+        F: FnOnce(&mut BatchWriter) -> io::Result<R>,
+    {
+        Batch::new(self, f)
+    }
+
+    /// Copies the decrypted data read from this stream into both `dst` and `tee`, such
+    /// as a backend and an audit log, until EOF, then flushes both.
+    ///
+    /// Each chunk is read once. A chunk is written to both writers before the next is
+    /// read, so the slower of the two sets the pace, while the faster one is never made
+    /// to wait except for new data. Both writers are flushed whenever this stream has
+    /// nothing to read, as with `tokio::io::copy`. The returned future resolves to the
+    /// number of bytes copied.
+    pub fn copy_with_tee<'a, W, T>(
+        &'a mut self,
+        dst: &'a mut W,
+        tee: &'a mut T,
+    ) -> CopyWithTee<'a, Self, W, T>
+    where
+        W: AsyncWrite + Unpin,
+        T: AsyncWrite + Unpin,
+    {
+        CopyWithTee::new(self, dst, tee)
+    }
+
+    /// Completes a handshake deferred by `TlsConnector::connect_lazy`.
+    ///
+    /// Early data keeps its own handling, which completes the handshake on flush.
+    #[inline]
+    fn poll_lazy_handshake(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if self.session.is_handshaking() && !self.state.is_early_data() {
+            self.poll_handshake(cx)
+        } else {
+            Poll::Ready(Ok(()))
+        }
+    }
+}
+
+impl<IO> TlsStream<KeepTrailingData<IO>> {
+    /// Returns the IO and the bytes the server sent after its `close_notify`, for
+    /// protocols that continue in plaintext once the TLS session ends.
+    ///
+    /// Fails, handing the stream back, unless the server's `close_notify` has been
+    /// received and all plaintext sent before it has been read.
+    #[allow(clippy::result_large_err)] // the stream is handed back as is
+    pub fn into_inner_after_close_notify(mut self) -> Result<(IO, Vec<u8>), Self> {
+        match self.session.process_new_packets() {
+            Ok(state) if state.peer_has_closed() && state.plaintext_bytes_to_read() == 0 => {
+                Ok(self.io.into_parts())
+            }
+            _ => Err(self),
+        }
+    }
+
+    /// Ends the TLS session but keeps the connection, for protocols that continue in
+    /// plaintext: sends `close_notify`, waits for the server's, and resolves to the IO
+    /// and any bytes the server sent after it.
+    ///
+    /// Plaintext that has not been read yet, or that the server sends before its
+    /// `close_notify`, makes the downgrade fail with `InvalidData`, since it would
+    /// otherwise be lost. On any failure the stream is handed back with the error, so
+    /// that such plaintext can still be read and the downgrade tried again.
+    #[inline]
+    pub fn downgrade(self) -> Downgrade<Self> {
+        Downgrade::new(self)
+    }
 }
 
 #[cfg(unix)]
@@ -68,11 +433,28 @@ impl<IO> IoSession for TlsStream<IO> {
         self.state.is_early_data()
     }
 
+    #[inline]
+    fn poll_deadline(&mut self, cx: &mut Context<'_>) -> io::Result<()> {
+        self.handshake_deadline.poll_check(cx)
+    }
+
+    #[inline]
+    fn handshake_phase(&self) -> HandshakePhase {
+        handshake_phase(&self.session)
+    }
+
     #[inline]
     fn get_mut(&mut self) -> (&mut TlsState, &mut Self::Io, &mut Self::Session) {
         (&mut self.state, &mut self.io, &mut self.session)
     }
 
+    #[inline]
+    fn handshake_completed(&mut self) {
+        self.handshake_completed_at.get_or_insert_with(Instant::now);
+        self.on_established.fire(&self.session);
+        self.handshake_deadline.clear();
+    }
+
     #[inline]
     fn into_io(self) -> Self::Io {
         self.io
@@ -84,25 +466,39 @@ where
     IO: AsyncRead + AsyncWrite + Unpin,
 {
     fn poll_read(
-        mut self: Pin<&mut Self>,
+        self: Pin<&mut Self>,
         cx: &mut Context<'_>,
         buf: &mut ReadBuf<'_>,
     ) -> Poll<io::Result<()>> {
         match self.state {
             #[cfg(feature = "early-data")]
             TlsState::EarlyData(..) => {
-                ready!(self.as_mut().poll_flush(cx))?;
-                self.as_mut().poll_read(cx, buf)
+                let this = self.get_mut();
+                ready!(this.poll_handshake(cx))?;
+                Pin::new(this).poll_read(cx, buf)
             }
             TlsState::Stream | TlsState::WriteShutdown => {
                 let this = self.get_mut();
-                let mut stream =
-                    Stream::new(&mut this.io, &mut this.session).set_eof(!this.state.readable());
+                ready!(this.poll_lazy_handshake(cx))?;
+
+                let mut stream = Stream::new(&mut this.io, &mut this.session)
+                    .set_eof(!this.state.readable())
+                    .set_read_batch(this.read_batch_limit);
                 let prev = buf.remaining();
 
-                match stream.as_mut_pin().poll_read(cx, buf) {
+                let output = match &mut this.read_rate_limit {
+                    Some(limit) => {
+                        limit.poll_read(cx, buf, |cx, buf| stream.as_mut_pin().poll_read(cx, buf))
+                    }
+                    None => stream.as_mut_pin().poll_read(cx, buf),
+                };
+                match output {
                     Poll::Ready(Ok(())) => {
-                        if prev == buf.remaining() || stream.eof {
+                        // `Stream::poll_read` only completes without data once rustls
+                        // has seen close_notify or EOF; while no plaintext is available
+                        // yet, it stays pending. A read into an empty buffer says nothing
+                        // about EOF.
+                        if prev != 0 && prev == buf.remaining() {
                             this.state.shutdown_read();
                         }
 
@@ -132,6 +528,9 @@ where
         buf: &[u8],
     ) -> Poll<io::Result<usize>> {
         let this = self.get_mut();
+        ready!(this.poll_lazy_handshake(cx))?;
+        this.key_update.apply(&mut this.session)?;
+
         let mut stream =
             Stream::new(&mut this.io, &mut this.session).set_eof(!this.state.readable());
 
@@ -140,6 +539,9 @@ where
             let bufs = [io::IoSlice::new(buf)];
             let written = ready!(poll_handle_early_data(
                 &mut this.state,
+                &mut this.handshake_completed_at,
+                &mut this.handshake_deadline,
+                &mut this.on_established,
                 &mut stream,
                 cx,
                 &bufs
@@ -160,6 +562,9 @@ where
         bufs: &[io::IoSlice<'_>],
     ) -> Poll<io::Result<usize>> {
         let this = self.get_mut();
+        ready!(this.poll_lazy_handshake(cx))?;
+        this.key_update.apply(&mut this.session)?;
+
         let mut stream =
             Stream::new(&mut this.io, &mut this.session).set_eof(!this.state.readable());
 
@@ -167,6 +572,9 @@ where
         {
             let written = ready!(poll_handle_early_data(
                 &mut this.state,
+                &mut this.handshake_completed_at,
+                &mut this.handshake_deadline,
+                &mut this.on_established,
                 &mut stream,
                 cx,
                 bufs
@@ -186,16 +594,24 @@ where
 
     fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
         let this = self.get_mut();
+        ready!(this.poll_lazy_handshake(cx))?;
+        this.key_update.apply(&mut this.session)?;
+
         let mut stream =
             Stream::new(&mut this.io, &mut this.session).set_eof(!this.state.readable());
 
         #[cfg(feature = "early-data")]
-        ready!(poll_handle_early_data(
-            &mut this.state,
-            &mut stream,
-            cx,
-            &[]
-        ))?;
+        if this.flush_completes_handshake {
+            ready!(poll_handle_early_data(
+                &mut this.state,
+                &mut this.handshake_completed_at,
+                &mut this.handshake_deadline,
+                &mut this.on_established,
+                &mut stream,
+                cx,
+                &[]
+            ))?;
+        }
 
         stream.as_mut_pin().poll_flush(cx)
     }
@@ -205,7 +621,7 @@ where
         {
             // complete handshake
             if matches!(self.state, TlsState::EarlyData(..)) {
-                ready!(self.as_mut().poll_flush(cx))?;
+                ready!(self.poll_handshake(cx))?;
             }
         }
 
@@ -221,9 +637,21 @@ where
     }
 }
 
+fn handshake_phase(session: &ClientConnection) -> HandshakePhase {
+    match (session.protocol_version(), session.wants_write()) {
+        (Some(_), _) => HandshakePhase::ServerHello,
+        // The ClientHello is queued when the connection is created.
+        (None, true) => HandshakePhase::Start,
+        (None, false) => HandshakePhase::ClientHello,
+    }
+}
+
 #[cfg(feature = "early-data")]
 fn poll_handle_early_data<IO>(
     state: &mut TlsState,
+    completed_at: &mut Option<Instant>,
+    deadline: &mut HandshakeDeadline,
+    established: &mut OnEstablished,
     stream: &mut Stream<IO, ClientConnection>,
     cx: &mut Context<'_>,
     bufs: &[io::IoSlice<'_>],
@@ -263,9 +691,16 @@ where
         }
 
         // complete handshake
+        if stream.session.is_handshaking() {
+            deadline.poll_check(cx)?;
+        }
         while stream.session.is_handshaking() {
-            ready!(stream.handshake(cx))?;
+            ready!(stream.handshake(cx))
+                .map_err(|err| HandshakeInterrupted::wrap(err, handshake_phase(stream.session)))?;
         }
+        completed_at.get_or_insert_with(Instant::now);
+        deadline.clear();
+        established.fire(stream.session);
 
         // write early data (fallback)
         if !stream.session.is_early_data_accepted() {
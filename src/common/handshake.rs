@@ -9,13 +9,17 @@ use rustls::{ConnectionCommon, SideData};
 use tokio::io::{AsyncRead, AsyncWrite};
 
 use crate::common::{Stream, SyncWriteAdapter, TlsState};
+use crate::{HandshakeInterrupted, HandshakePhase};
 
 pub(crate) trait IoSession {
     type Io;
     type Session;
 
     fn skip_handshake(&self) -> bool;
+    fn poll_deadline(&mut self, cx: &mut Context<'_>) -> io::Result<()>;
+    fn handshake_phase(&self) -> HandshakePhase;
     fn get_mut(&mut self) -> (&mut TlsState, &mut Self::Io, &mut Self::Session);
+    fn handshake_completed(&mut self);
     fn into_io(self) -> Self::Io;
 }
 
@@ -67,6 +71,10 @@ where
         };
 
         if !stream.skip_handshake() {
+            if let Err(err) = stream.poll_deadline(cx) {
+                return Poll::Ready(Err((err, stream.into_io())));
+            }
+
             let (state, io, session) = stream.get_mut();
             let mut tls_stream = Stream::new(io, session).set_eof(!state.readable());
 
@@ -74,7 +82,10 @@ where
                 ( $e:expr ) => {
                     match $e {
                         Poll::Ready(Ok(_)) => (),
-                        Poll::Ready(Err(err)) => return Poll::Ready(Err((err, stream.into_io()))),
+                        Poll::Ready(Err(err)) => {
+                            let err = HandshakeInterrupted::wrap(err, stream.handshake_phase());
+                            return Poll::Ready(Err((err, stream.into_io())));
+                        }
                         Poll::Pending => {
                             *this = MidHandshake::Handshaking(stream);
                             return Poll::Pending;
@@ -88,6 +99,7 @@ where
             }
 
             try_poll!(Pin::new(&mut tls_stream).poll_flush(cx));
+            stream.handshake_completed();
         }
 
         Poll::Ready(Ok(stream))
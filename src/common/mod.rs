@@ -1,14 +1,44 @@
 use std::io::{self, IoSlice, Read, Write};
 use std::ops::{Deref, DerefMut};
 use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::task::{Context, Poll};
 
-use rustls::{ConnectionCommon, SideData};
+use rustls::{CommonState, ConnectionCommon, SideData};
 use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
 
+use crate::HttpVersion;
+
 mod handshake;
 pub(crate) use handshake::{IoSession, MidHandshake};
 
+/// Hands out a process-wide unique ID for each new `TlsStream`.
+pub(crate) fn next_connection_id() -> u64 {
+    static NEXT: AtomicU64 = AtomicU64::new(1);
+    NEXT.fetch_add(1, Ordering::Relaxed)
+}
+
+/// The plaintext not read yet and the TLS data not written yet; see
+/// `client::TlsStream::buffered_bytes`.
+pub(crate) fn buffered_bytes<D>(session: &mut ConnectionCommon<D>) -> io::Result<(usize, usize)> {
+    let state = session
+        .process_new_packets()
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+    Ok((state.plaintext_bytes_to_read(), state.tls_bytes_to_write()))
+}
+
+/// See `client::TlsStream::key_exchange_group_name`.
+pub(crate) fn key_exchange_group_name(state: &CommonState) -> Option<&'static str> {
+    state
+        .negotiated_key_exchange_group()
+        .and_then(|group| group.name().as_str())
+}
+
+/// See `client::TlsStream::negotiated_http_version`.
+pub(crate) fn negotiated_http_version(state: &CommonState) -> Option<HttpVersion> {
+    state.alpn_protocol().and_then(HttpVersion::from_alpn)
+}
+
 #[derive(Debug)]
 pub enum TlsState {
     #[cfg(feature = "early-data")]
@@ -63,6 +93,7 @@ pub struct Stream<'a, IO, C> {
     pub io: &'a mut IO,
     pub session: &'a mut C,
     pub eof: bool,
+    pub read_batch: usize,
 }
 
 impl<'a, IO: AsyncRead + AsyncWrite + Unpin, C, SD> Stream<'a, IO, C>
@@ -77,6 +108,7 @@ where
             // The state so far is only used to detect EOF, so either Stream
             // or EarlyData state should both be all right.
             eof: false,
+            read_batch: 1,
         }
     }
 
@@ -85,6 +117,11 @@ where
         self
     }
 
+    pub fn set_read_batch(mut self, read_batch: usize) -> Self {
+        self.read_batch = read_batch;
+        self
+    }
+
     pub fn as_mut_pin(&mut self) -> Pin<&mut Self> {
         Pin::new(self)
     }
@@ -98,6 +135,12 @@ where
             Err(err) => return Poll::Ready(Err(err)),
         };
 
+        self.process_new_packets(cx)?;
+
+        Poll::Ready(Ok(n))
+    }
+
+    fn process_new_packets(&mut self, cx: &mut Context) -> io::Result<rustls::IoState> {
         self.session.process_new_packets().map_err(|err| {
             // In case we have an alert to send describing this error,
             // try a last-gasp write -- but don't predate the primary
@@ -105,9 +148,22 @@ where
             let _ = self.write_io(cx);
 
             io::Error::new(io::ErrorKind::InvalidData, err)
-        })?;
+        })
+    }
 
-        Poll::Ready(Ok(n))
+    /// Reads TLS data until plaintext is buffered or no more is coming, without
+    /// taking any of the plaintext.
+    pub fn poll_data_available(&mut self, cx: &mut Context) -> Poll<io::Result<()>> {
+        loop {
+            let state = self.process_new_packets(cx)?;
+            if state.plaintext_bytes_to_read() > 0 || state.peer_has_closed() || self.eof {
+                return Poll::Ready(Ok(()));
+            }
+
+            if ready!(self.read_io(cx))? == 0 {
+                self.eof = true;
+            }
+        }
     }
 
     pub fn write_io(&mut self, cx: &mut Context) -> Poll<io::Result<usize>> {
@@ -194,6 +250,9 @@ where
         let mut io_pending = false;
 
         // read a packet
+        //
+        // rustls keeps wanting to read until it has plaintext to yield, so records
+        // that carry no application data (such as a key update) don't end the loop.
         while !self.eof && self.session.wants_read() {
             match self.read_io(cx) {
                 Poll::Ready(Ok(0)) => {
@@ -208,6 +267,33 @@ where
             }
         }
 
+        // With a read batch, keep draining ciphertext that is ready, for as long as the
+        // caller's buffer could take more plaintext than is buffered.
+        if self.read_batch > 1 && !io_pending && !self.eof {
+            let mut buffered = self.process_new_packets(cx)?.plaintext_bytes_to_read();
+            for _ in 1..self.read_batch {
+                if buffered >= buf.remaining() || io_pending || self.eof {
+                    break;
+                }
+
+                let this = &mut *self;
+                let mut reader = ObservedRead {
+                    inner: SyncReadAdapter { io: this.io, cx },
+                    used: false,
+                };
+                match this.session.read_tls(&mut reader) {
+                    Ok(0) => self.eof = true,
+                    Ok(_) => (),
+                    Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => io_pending = true,
+                    // rustls refuses more TLS data, without reading, once its plaintext
+                    // buffer is full; what is buffered is handed over first.
+                    Err(_) if !reader.used => break,
+                    Err(err) => return Poll::Ready(Err(err)),
+                }
+                buffered = self.process_new_packets(cx)?.plaintext_bytes_to_read();
+            }
+        }
+
         match self.session.reader().read(buf.initialize_unfilled()) {
             // If Rustls returns `Ok(0)` (while `buf` is non-empty), the peer closed the
             // connection with a `CloseNotify` message and no more data will be forthcoming.
@@ -362,6 +448,20 @@ impl<'a, 'b, T: AsyncRead + Unpin> Read for SyncReadAdapter<'a, 'b, T> {
     }
 }
 
+/// A [`Read`] that notes whether it has been read from.
+struct ObservedRead<R> {
+    inner: R,
+    used: bool,
+}
+
+impl<R: Read> Read for ObservedRead<R> {
+    #[inline]
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.used = true;
+        self.inner.read(buf)
+    }
+}
+
 /// An adapter that implements a [`Write`] interface for [`AsyncWrite`] types and an
 /// associated [`Context`].
 ///
@@ -8,7 +8,9 @@ use futures_util::task::noop_waker_ref;
 use rustls::{ClientConnection, Connection, ServerConnection};
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
 
-use super::Stream;
+use super::{Stream, TlsState};
+use crate::deadline::HandshakeDeadline;
+use crate::{client, server, HandshakeInterrupted, HandshakePhase};
 
 struct Good<'a>(&'a mut Connection);
 
@@ -121,6 +123,126 @@ impl AsyncWrite for Expected {
     }
 }
 
+/// Yields its contents `chunk` bytes at a time, returning `Pending` before each chunk.
+struct Chunked {
+    data: Cursor<Vec<u8>>,
+    chunk: usize,
+    ready: bool,
+}
+
+impl AsyncRead for Chunked {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if !this.ready {
+            this.ready = true;
+            cx.waker().wake_by_ref();
+            return Poll::Pending;
+        }
+
+        this.ready = false;
+        let len = buf.remaining().min(this.chunk);
+        let n = std::io::Read::read(&mut this.data, buf.initialize_unfilled_to(len))?;
+        buf.advance(n);
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for Chunked {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Yields one queued chunk per read, then stays pending.
+struct Chunks(std::collections::VecDeque<Vec<u8>>);
+
+impl AsyncRead for Chunks {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut().0.pop_front() {
+            Some(chunk) => {
+                buf.put_slice(&chunk);
+                Poll::Ready(Ok(()))
+            }
+            None => Poll::Pending,
+        }
+    }
+}
+
+impl AsyncWrite for Chunks {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Yields its queued chunks like `Chunks`, then fails with `ConnectionReset` once.
+struct ResetAfter(Chunks, bool);
+
+impl AsyncRead for ResetAfter {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.0 .0.is_empty() && !this.1 {
+            this.1 = true;
+            return Poll::Ready(Err(io::ErrorKind::ConnectionReset.into()));
+        }
+        Pin::new(&mut this.0).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for ResetAfter {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().0).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().0).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().0).poll_shutdown(cx)
+    }
+}
+
 #[tokio::test]
 async fn stream_good() -> io::Result<()> {
     stream_good_impl(false).await
@@ -290,6 +412,329 @@ async fn stream_eof() -> io::Result<()> {
     Ok(()) as io::Result<()>
 }
 
+#[tokio::test]
+async fn stream_read_split_record() -> io::Result<()> {
+    let (server, client) = make_pair();
+    let mut server = Connection::from(server);
+    let mut client = Connection::from(client);
+
+    // Complete the handshake in memory; `Good` would signal EOF once idle.
+    handshake_in_memory(&mut client, &mut server)?;
+
+    server.writer().write_all(b"Hello World!")?;
+    server.send_close_notify();
+    let mut data = Vec::new();
+    while server.wants_write() {
+        server.write_tls(&mut data)?;
+    }
+
+    // Deliver the ciphertext in pieces, so that no plaintext is available
+    // until the second underlying read.
+    let chunk = data.len() / 2 + 1;
+    let mut stream = client_stream(
+        Chunked {
+            data: Cursor::new(data),
+            chunk,
+            ready: true,
+        },
+        client,
+    );
+
+    let mut cx = Context::from_waker(noop_waker_ref());
+    let mut buf = [0; 32];
+    let mut read_buf = ReadBuf::new(&mut buf);
+    assert!(Pin::new(&mut stream)
+        .poll_read(&mut cx, &mut read_buf)
+        .is_pending());
+    assert!(read_buf.filled().is_empty());
+    assert!(stream.state.readable());
+
+    // Reading into an empty buffer must not be mistaken for EOF either.
+    let mut empty = ReadBuf::new(&mut []);
+    assert!(matches!(
+        Pin::new(&mut stream).poll_read(&mut cx, &mut empty),
+        Poll::Ready(Ok(()))
+    ));
+    assert!(stream.state.readable());
+
+    let mut buf = Vec::new();
+    stream.read_to_end(&mut buf).await?;
+    assert_eq!(buf, b"Hello World!");
+    assert!(!stream.state.readable());
+
+    Ok(()) as io::Result<()>
+}
+
+#[tokio::test]
+async fn stream_read_across_key_update() -> io::Result<()> {
+    let (server, client) = make_pair();
+    let mut server = Connection::from(server);
+    let mut client = Connection::from(client);
+
+    handshake_in_memory(&mut client, &mut server)?;
+
+    let mut chunks = std::collections::VecDeque::new();
+    for step in 0..3 {
+        match step {
+            0 => server.writer().write_all(b"Hello")?,
+            1 => server
+                .refresh_traffic_keys()
+                .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?,
+            _ => server.writer().write_all(b" World!")?,
+        }
+
+        let mut chunk = Vec::new();
+        while server.wants_write() {
+            server.write_tls(&mut chunk)?;
+        }
+        chunks.push_back(chunk);
+    }
+
+    let mut stream = client_stream(Chunks(chunks), client);
+
+    let mut cx = Context::from_waker(noop_waker_ref());
+    let mut buf = [0; 32];
+    let mut read_buf = ReadBuf::new(&mut buf);
+    assert!(matches!(
+        Pin::new(&mut stream).poll_read(&mut cx, &mut read_buf),
+        Poll::Ready(Ok(()))
+    ));
+    assert_eq!(read_buf.filled(), b"Hello");
+
+    // The key update arrives in a read of its own, but must not cut the read short.
+    let mut read_buf = ReadBuf::new(&mut buf);
+    assert!(matches!(
+        Pin::new(&mut stream).poll_read(&mut cx, &mut read_buf),
+        Poll::Ready(Ok(()))
+    ));
+    assert_eq!(read_buf.filled(), b" World!");
+    assert!(stream.state.readable());
+
+    Ok(()) as io::Result<()>
+}
+
+#[tokio::test]
+async fn stream_read_tampered_record() -> io::Result<()> {
+    let (server, client) = make_pair();
+    let mut server = Connection::from(server);
+    let mut client = Connection::from(client);
+
+    handshake_in_memory(&mut client, &mut server)?;
+
+    server.writer().write_all(b"Hello")?;
+    let mut chunk = Vec::new();
+    while server.wants_write() {
+        server.write_tls(&mut chunk)?;
+    }
+    // Flip a bit in the AEAD tag.
+    *chunk.last_mut().unwrap() ^= 1;
+
+    let mut stream = client_stream(Chunks(vec![chunk].into()), client);
+
+    let mut buf = [0; 32];
+    let err = stream.read(&mut buf).await.unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    assert_eq!(
+        err.get_ref()
+            .and_then(|err| err.downcast_ref::<rustls::Error>()),
+        Some(&rustls::Error::DecryptError)
+    );
+
+    Ok(()) as io::Result<()>
+}
+
+#[tokio::test]
+async fn stream_read_batch() -> io::Result<()> {
+    for (batch, expected) in [(1, &b"one"[..]), (3, &b"onetwothree"[..])] {
+        let (server, client) = make_pair();
+        let mut server = Connection::from(server);
+        let mut client = Connection::from(client);
+
+        handshake_in_memory(&mut client, &mut server)?;
+
+        // One record per read from the IO.
+        let mut chunks = std::collections::VecDeque::new();
+        for data in [&b"one"[..], b"two", b"three"] {
+            server.writer().write_all(data)?;
+            let mut chunk = Vec::new();
+            while server.wants_write() {
+                server.write_tls(&mut chunk)?;
+            }
+            chunks.push_back(chunk);
+        }
+
+        let mut stream = client_stream(Chunks(chunks), client);
+        stream.set_read_batch_limit(batch);
+
+        let mut buf = [0; 32];
+        let n = stream.read(&mut buf).await?;
+        assert_eq!(&buf[..n], expected);
+    }
+
+    Ok(()) as io::Result<()>
+}
+
+#[tokio::test]
+async fn server_stream_read_batch() -> io::Result<()> {
+    let (server, client) = make_pair();
+    let mut server = Connection::from(server);
+    let mut client = Connection::from(client);
+
+    handshake_in_memory(&mut client, &mut server)?;
+
+    // One record per read from the IO, then EOF without a close_notify.
+    let mut chunks = std::collections::VecDeque::new();
+    for data in [&b"one"[..], b"two", b"three"] {
+        client.writer().write_all(data)?;
+        let mut chunk = Vec::new();
+        while client.wants_write() {
+            client.write_tls(&mut chunk)?;
+        }
+        chunks.push_back(chunk);
+    }
+    // Like a socket, the IO keeps reporting EOF once it has.
+    chunks.extend([Vec::new(), Vec::new()]);
+
+    let mut stream = match server {
+        Connection::Server(server) => server::TlsStream::new(Chunks(chunks), server),
+        Connection::Client(_) => unreachable!(),
+    };
+    stream.set_read_batch_limit(4);
+
+    let mut buf = [0; 32];
+    let n = stream.read(&mut buf).await?;
+    assert_eq!(&buf[..n], b"onetwothree");
+
+    // The EOF reached within the batch still reads as a truncated stream.
+    let err = stream.read(&mut buf).await.unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+
+    Ok(()) as io::Result<()>
+}
+
+#[tokio::test]
+async fn server_poll_handshake_deadline() -> io::Result<()> {
+    let (server, _client) = make_pair();
+    let mut stream = server::TlsStream::new(Pending, server);
+    stream.handshake_deadline = HandshakeDeadline::new(Some(std::time::Duration::from_millis(10)));
+
+    let err = poll_fn(|cx| stream.poll_handshake(cx)).await.unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+
+    Ok(()) as io::Result<()>
+}
+
+#[tokio::test]
+async fn server_poll_handshake_interrupted() -> io::Result<()> {
+    let (server, _client) = make_pair();
+    let mut stream = server::TlsStream::new(Chunks(vec![Vec::new()].into()), server);
+
+    let err = poll_fn(|cx| stream.poll_handshake(cx)).await.unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    assert_eq!(
+        err.get_ref()
+            .and_then(|err| err.downcast_ref::<HandshakeInterrupted>())
+            .map(HandshakeInterrupted::phase),
+        Some(HandshakePhase::Start)
+    );
+
+    Ok(()) as io::Result<()>
+}
+
+#[tokio::test]
+async fn stream_read_batch_io_error() -> io::Result<()> {
+    let (server, client) = make_pair();
+    let mut server = Connection::from(server);
+    let mut client = Connection::from(client);
+
+    handshake_in_memory(&mut client, &mut server)?;
+
+    server.writer().write_all(b"one")?;
+    let mut chunk = Vec::new();
+    while server.wants_write() {
+        server.write_tls(&mut chunk)?;
+    }
+
+    let mut stream = client_stream(ResetAfter(Chunks(vec![chunk].into()), false), client);
+    stream.set_read_batch_limit(3);
+
+    // The reset is reported as it happens, though plaintext is buffered ahead of it.
+    let mut buf = [0; 32];
+    let err = stream.read(&mut buf).await.unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+    let n = stream.read(&mut buf).await?;
+    assert_eq!(&buf[..n], b"one");
+
+    Ok(()) as io::Result<()>
+}
+
+#[tokio::test]
+async fn stream_read_batch_full() -> io::Result<()> {
+    let (server, client) = make_pair();
+    let mut server = Connection::from(server);
+    let mut client = Connection::from(client);
+
+    handshake_in_memory(&mut client, &mut server)?;
+
+    let mut chunks = std::collections::VecDeque::new();
+    for _ in 0..10 {
+        server.writer().write_all(&[0; 2000])?;
+        let mut chunk = Vec::new();
+        while server.wants_write() {
+            server.write_tls(&mut chunk)?;
+        }
+        chunks.push_back(chunk);
+    }
+
+    let mut stream = client_stream(Chunks(chunks), client);
+    stream.set_read_batch_limit(20);
+
+    // The batch stops once rustls holds as much plaintext as it will buffer.
+    let mut buf = vec![0; 64 * 1024];
+    let first = stream.read(&mut buf).await?;
+    assert!(first < 20_000);
+    let second = stream.read(&mut buf).await?;
+    assert_eq!(first + second, 20_000);
+
+    Ok(()) as io::Result<()>
+}
+
+fn handshake_in_memory(client: &mut Connection, server: &mut Connection) -> io::Result<()> {
+    while client.is_handshaking()
+        || server.is_handshaking()
+        || client.wants_write()
+        || server.wants_write()
+    {
+        transfer(client, server)?;
+        transfer(server, client)?;
+    }
+
+    Ok(())
+}
+
+fn transfer(from: &mut Connection, to: &mut Connection) -> io::Result<()> {
+    let mut buf = Vec::new();
+    while from.wants_write() {
+        from.write_tls(&mut buf)?;
+    }
+
+    if !buf.is_empty() {
+        to.read_tls(&mut &buf[..])?;
+        to.process_new_packets()
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+    }
+
+    Ok(())
+}
+
+/// Wraps the client half of a pair set up by `handshake_in_memory`.
+fn client_stream<IO>(io: IO, session: Connection) -> client::TlsStream<IO> {
+    match session {
+        Connection::Client(session) => client::TlsStream::new(io, session, TlsState::Stream),
+        Connection::Server(_) => unreachable!(),
+    }
+}
+
 fn make_pair() -> (ServerConnection, ClientConnection) {
     let (sconfig, cconfig) = utils::make_configs();
     let server = ServerConnection::new(Arc::new(sconfig)).unwrap();
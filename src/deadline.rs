@@ -0,0 +1,48 @@
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use tokio::time::Sleep;
+
+/// Bounds the wall-clock time a handshake may take, from the first time it is
+/// driven until it completes, however much progress it makes in between.
+#[derive(Debug, Default)]
+pub(crate) struct HandshakeDeadline {
+    limit: Option<Duration>,
+    sleep: Option<Pin<Box<Sleep>>>,
+}
+
+impl HandshakeDeadline {
+    pub(crate) fn new(limit: Option<Duration>) -> Self {
+        HandshakeDeadline { limit, sleep: None }
+    }
+
+    /// Fails with `TimedOut` once the deadline has passed; until then, arranges for
+    /// the task to be woken when it does.
+    pub(crate) fn poll_check(&mut self, cx: &mut Context<'_>) -> io::Result<()> {
+        let limit = match self.limit {
+            Some(limit) => limit,
+            None => return Ok(()),
+        };
+
+        let sleep = self
+            .sleep
+            .get_or_insert_with(|| Box::pin(tokio::time::sleep(limit)));
+        match sleep.as_mut().poll(cx) {
+            Poll::Ready(()) => Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "handshake did not complete in time",
+            )),
+            Poll::Pending => Ok(()),
+        }
+    }
+
+    /// Stops the timer once the handshake has completed.
+    #[inline]
+    pub(crate) fn clear(&mut self) {
+        self.limit = None;
+        self.sleep = None;
+    }
+}
@@ -0,0 +1,125 @@
+use std::time::{Duration, SystemTime};
+
+const UTC_TIME: u8 = 0x17;
+const GENERALIZED_TIME: u8 = 0x18;
+
+/// Returns the notBefore and notAfter times of a DER certificate.
+pub(crate) fn validity(cert: &[u8]) -> Option<(SystemTime, SystemTime)> {
+    let mut tbs = tbs_certificate(cert)?;
+
+    // serialNumber, signature, issuer
+    for _ in 0..3 {
+        tbs = element(tbs)?.2;
+    }
+
+    let (_, validity, _) = element(tbs)?;
+    let (not_before, _, rest) = element(validity)?;
+    let (not_after, _, _) = element(rest)?;
+    Some((time(not_before)?, time(not_after)?))
+}
+
+/// Returns the subject Name of a DER certificate, as a whole DER element.
+pub(crate) fn subject(cert: &[u8]) -> Option<&[u8]> {
+    let mut tbs = tbs_certificate(cert)?;
+
+    // serialNumber, signature, issuer, validity
+    for _ in 0..4 {
+        tbs = element(tbs)?.2;
+    }
+
+    Some(element(tbs)?.0)
+}
+
+/// Returns the fields of the TBSCertificate in a DER certificate, starting at
+/// serialNumber.
+pub(crate) fn tbs_certificate(cert: &[u8]) -> Option<&[u8]> {
+    const VERSION: u8 = 0xa0;
+
+    let (_, cert, _) = element(cert)?;
+    let (_, mut tbs, _) = element(cert)?;
+    if tbs.first() == Some(&VERSION) {
+        tbs = element(tbs)?.2;
+    }
+    Some(tbs)
+}
+
+/// Splits the first DER element off `input`: the whole element, its contents and
+/// the remaining input.
+pub(crate) fn element(input: &[u8]) -> Option<(&[u8], &[u8], &[u8])> {
+    let (&first, rest) = input.get(1..)?.split_first()?;
+    let (len, rest) = match first {
+        len @ 0..=0x7f => (usize::from(len), rest),
+        0x81..=0x84 => {
+            let n = usize::from(first & 0x7f);
+            let len = rest
+                .get(..n)?
+                .iter()
+                .fold(0, |len, &b| len << 8 | usize::from(b));
+            (len, &rest[n..])
+        }
+        _ => return None,
+    };
+
+    let contents = rest.get(..len)?;
+    let header = input.len() - rest.len();
+    Some((&input[..header + len], contents, &rest[len..]))
+}
+
+/// Parses a UTCTime or GeneralizedTime element in the form RFC 5280 requires:
+/// UTC, to the second, with no fraction.
+fn time(element: &[u8]) -> Option<SystemTime> {
+    let (&tag, rest) = element.split_first()?;
+    let text = rest.get(1..)?;
+    let (year, text) = match (tag, text.len()) {
+        (UTC_TIME, 13) => {
+            // Two-digit years 50 to 99 are in the 1900s, the rest in the 2000s.
+            let year = digits(&text[..2])?;
+            (if year >= 50 { 1900 } else { 2000 } + year, &text[2..])
+        }
+        (GENERALIZED_TIME, 15) => (digits(&text[..4])?, &text[4..]),
+        _ => return None,
+    };
+    if text[10] != b'Z' {
+        return None;
+    }
+
+    let month = digits(&text[0..2])?;
+    let day = digits(&text[2..4])?;
+    let hour = digits(&text[4..6])?;
+    let minute = digits(&text[6..8])?;
+    let second = digits(&text[8..10])?;
+    if !(1..=12).contains(&month)
+        || !(1..=31).contains(&day)
+        || hour > 23
+        || minute > 59
+        || second > 59
+    {
+        return None;
+    }
+
+    let days = days_from_civil(year, month, day);
+    let secs = days * 86_400 + hour * 3_600 + minute * 60 + second;
+    match u64::try_from(secs) {
+        Ok(secs) => SystemTime::UNIX_EPOCH.checked_add(Duration::from_secs(secs)),
+        Err(_) => SystemTime::UNIX_EPOCH.checked_sub(Duration::from_secs(secs.unsigned_abs())),
+    }
+}
+
+fn digits(text: &[u8]) -> Option<i64> {
+    text.iter().try_fold(0, |n, &b| match b {
+        b'0'..=b'9' => Some(n * 10 + i64::from(b - b'0')),
+        _ => None,
+    })
+}
+
+/// The number of days from 1970-01-01 to the given date in the proleptic Gregorian
+/// calendar, after Howard Hinnant's `days_from_civil`.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month_from_march = (month + 9) % 12;
+    let day_of_year = (153 * month_from_march + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
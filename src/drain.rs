@@ -0,0 +1,89 @@
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use tokio::io::AsyncWrite;
+
+/// Gracefully shuts down many streams concurrently, giving up on those that do not
+/// finish before `deadline` resolves.
+///
+/// Each stream is driven through `poll_shutdown`, which for a `TlsStream` sends a
+/// `close_notify` alert and flushes it to the underlying IO. The peer's `close_notify`
+/// is not awaited, since that requires reading (and discarding) any application data
+/// still in flight.
+///
+/// `deadline` may be any future, such as `tokio::time::sleep_until(..)`. Once it
+/// resolves, the remaining streams are dropped, closing their underlying IO, and
+/// reported with `io::ErrorKind::TimedOut`.
+///
+/// The returned future resolves to one result per stream, in the order they were given.
+/// Every poll visits all streams that are still shutting down.
+pub fn drain<S, D>(streams: impl IntoIterator<Item = S>, deadline: D) -> Drain<S, D>
+where
+    S: AsyncWrite + Unpin,
+    D: Future,
+{
+    let streams = streams.into_iter().map(Some).collect::<Vec<_>>();
+    let results = streams.iter().map(|_| None).collect();
+    Drain {
+        streams,
+        results,
+        deadline: Box::pin(deadline),
+    }
+}
+
+/// Future returned from [`drain`].
+pub struct Drain<S, D> {
+    streams: Vec<Option<S>>,
+    results: Vec<Option<io::Result<()>>>,
+    deadline: Pin<Box<D>>,
+}
+
+impl<S, D> Future for Drain<S, D>
+where
+    S: AsyncWrite + Unpin,
+    D: Future,
+{
+    type Output = Vec<io::Result<()>>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let mut pending = false;
+
+        for (slot, result) in this.streams.iter_mut().zip(this.results.iter_mut()) {
+            let stream = match slot {
+                Some(stream) => stream,
+                None => continue,
+            };
+
+            match Pin::new(stream).poll_shutdown(cx) {
+                Poll::Ready(res) => {
+                    *slot = None;
+                    *result = Some(res);
+                }
+                Poll::Pending => pending = true,
+            }
+        }
+
+        if pending && this.deadline.as_mut().poll(cx).is_pending() {
+            return Poll::Pending;
+        }
+
+        let results = this
+            .streams
+            .drain(..)
+            .zip(this.results.drain(..))
+            .map(|(_, result)| {
+                result.unwrap_or_else(|| {
+                    Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        "shutdown did not complete before the deadline",
+                    ))
+                })
+            })
+            .collect();
+
+        Poll::Ready(results)
+    }
+}
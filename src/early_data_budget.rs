@@ -0,0 +1,59 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// Bounds how many connections, across every `TlsAcceptor` sharing it, may be
+/// offered 0-RTT at the same time.
+///
+/// Each `accept` takes a slot for the duration of its handshake. Once all slots are
+/// taken, further connections reject early data, so clients fall back to sending it
+/// again after a full 1-RTT handshake. As each connection buffers at most
+/// `ServerConfig::max_early_data_size` bytes of early data, this caps the memory, and
+/// the replay-cache entries, that 0-RTT can tie up at once.
+///
+/// Slots are held from the start of the handshake, before it is known whether the
+/// client will send early data, and returned when the handshake completes or the
+/// stream is dropped.
+#[derive(Clone, Debug)]
+pub struct EarlyDataBudget(Arc<Budget>);
+
+#[derive(Debug)]
+struct Budget {
+    max: usize,
+    in_use: AtomicUsize,
+}
+
+impl EarlyDataBudget {
+    /// A budget of `max_connections` concurrent handshakes.
+    pub fn new(max_connections: usize) -> Self {
+        EarlyDataBudget(Arc::new(Budget {
+            max: max_connections,
+            in_use: AtomicUsize::new(0),
+        }))
+    }
+
+    /// The number of slots currently taken.
+    #[inline]
+    pub fn in_use(&self) -> usize {
+        self.0.in_use.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn try_acquire(&self) -> Option<EarlyDataPermit> {
+        self.0
+            .in_use
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| {
+                (n < self.0.max).then(|| n + 1)
+            })
+            .ok()
+            .map(|_| EarlyDataPermit(self.0.clone()))
+    }
+}
+
+/// A slot taken from an `EarlyDataBudget`, returned on drop.
+#[derive(Debug)]
+pub(crate) struct EarlyDataPermit(Arc<Budget>);
+
+impl Drop for EarlyDataPermit {
+    fn drop(&mut self) {
+        self.0.in_use.fetch_sub(1, Ordering::AcqRel);
+    }
+}
@@ -0,0 +1,137 @@
+use std::io;
+use std::ops::{Deref, DerefMut};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// Counts the plaintext bytes read from and written to a stream.
+///
+/// `Instrumented` derefs to the wrapped stream, so the accessors of a `TlsStream`
+/// remain reachable through it.
+#[derive(Debug)]
+pub struct Instrumented<S> {
+    inner: S,
+    bytes_read: u64,
+    bytes_written: u64,
+}
+
+impl<S> Instrumented<S> {
+    #[inline]
+    pub fn new(inner: S) -> Self {
+        Instrumented {
+            inner,
+            bytes_read: 0,
+            bytes_written: 0,
+        }
+    }
+
+    /// The number of bytes read through this wrapper.
+    #[inline]
+    pub fn bytes_read(&self) -> u64 {
+        self.bytes_read
+    }
+
+    /// The number of bytes written through this wrapper.
+    #[inline]
+    pub fn bytes_written(&self) -> u64 {
+        self.bytes_written
+    }
+
+    /// Returns the counters and zeroes them, for measuring per-interval throughput.
+    ///
+    /// This takes `&mut self`, so no read or write can happen between taking the
+    /// snapshot and the reset.
+    #[inline]
+    pub fn reset_stats(&mut self) -> IoStats {
+        let stats = IoStats {
+            bytes_read: self.bytes_read,
+            bytes_written: self.bytes_written,
+        };
+        self.bytes_read = 0;
+        self.bytes_written = 0;
+        stats
+    }
+
+    #[inline]
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+/// A snapshot of the counters of an [`Instrumented`] stream.
+#[non_exhaustive]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct IoStats {
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+}
+
+impl<S> Deref for Instrumented<S> {
+    type Target = S;
+
+    #[inline]
+    fn deref(&self) -> &S {
+        &self.inner
+    }
+}
+
+impl<S> DerefMut for Instrumented<S> {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut S {
+        &mut self.inner
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Instrumented<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let prev = buf.filled().len();
+        let res = ready!(Pin::new(&mut this.inner).poll_read(cx, buf));
+        this.bytes_read += (buf.filled().len() - prev) as u64;
+        Poll::Ready(res)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Instrumented<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let n = ready!(Pin::new(&mut this.inner).poll_write(cx, buf))?;
+        this.bytes_written += n as u64;
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let n = ready!(Pin::new(&mut this.inner).poll_write_vectored(cx, bufs))?;
+        this.bytes_written += n as u64;
+        Poll::Ready(Ok(n))
+    }
+
+    #[inline]
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    #[inline]
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    #[inline]
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,82 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+/// How far a handshake had got when the transport failed; see
+/// [`HandshakeInterrupted`].
+#[non_exhaustive]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HandshakePhase {
+    /// A client had not sent its ClientHello yet, or a server had not received a
+    /// complete ClientHello.
+    Start,
+    /// A client had sent its ClientHello, but not received the ServerHello.
+    ClientHello,
+    /// The hellos had been exchanged, settling the protocol version and key exchange,
+    /// and the rest of the handshake, such as certificates and Finished, was under way.
+    ServerHello,
+}
+
+impl fmt::Display for HandshakePhase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            HandshakePhase::Start => "before the hello was exchanged",
+            HandshakePhase::ClientHello => "after ClientHello",
+            HandshakePhase::ServerHello => "after ServerHello",
+        })
+    }
+}
+
+/// The error inside an `io::Error` when the peer goes away during the handshake, by
+/// resetting or closing the connection, recording how far the handshake had got.
+///
+/// The `io::Error` keeps the kind of the transport error, such as `ConnectionReset`
+/// or `UnexpectedEof`, so this only needs to be looked for when the phase matters:
+///
+/// ```no_run
+/// # fn log(err: std::io::Error) {
+/// use tokio_rustls::HandshakeInterrupted;
+///
+/// match err.get_ref().and_then(|err| err.downcast_ref::<HandshakeInterrupted>()) {
+///     Some(interrupted) => println!("handshake failed {}", interrupted.phase()),
+///     None => println!("handshake failed: {}", err),
+/// }
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct HandshakeInterrupted {
+    phase: HandshakePhase,
+    source: io::Error,
+}
+
+impl HandshakeInterrupted {
+    /// Wraps `err` if it says the peer went away; other errors are returned as is.
+    pub(crate) fn wrap(err: io::Error, phase: HandshakePhase) -> io::Error {
+        match err.kind() {
+            io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::BrokenPipe
+            | io::ErrorKind::UnexpectedEof => {
+                io::Error::new(err.kind(), HandshakeInterrupted { phase, source: err })
+            }
+            _ => err,
+        }
+    }
+
+    #[inline]
+    pub fn phase(&self) -> HandshakePhase {
+        self.phase
+    }
+}
+
+impl fmt::Display for HandshakeInterrupted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.source, self.phase)
+    }
+}
+
+impl Error for HandshakeInterrupted {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&self.source)
+    }
+}
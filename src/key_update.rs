@@ -0,0 +1,60 @@
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use rustls::{ConnectionCommon, SideData};
+
+/// Requests a TLS 1.3 key update on a stream from another task, such as a timer
+/// driving key rotation, without access to the stream itself.
+///
+/// Obtained from `TlsStream::key_update_handle`. The stream calls
+/// `refresh_traffic_keys` at its next write or flush once the handshake is complete,
+/// so the `key_update` message is sent ahead of that write's data.
+///
+/// **A request does not wake the stream.** Nothing is sent until the task owning the
+/// stream next writes or flushes it; reads do not apply the update. A stream that is
+/// only read from, or idle, never sends it, so a task rotating keys on a timer must
+/// also arrange for the stream to be flushed.
+///
+/// TLS 1.2 has no key updates: once requested on such a connection, that write fails
+/// with `ErrorKind::Unsupported`.
+#[derive(Clone, Debug)]
+pub struct KeyUpdateHandle(Arc<AtomicBool>);
+
+impl KeyUpdateHandle {
+    /// Requests a key update. Requests made before the stream has applied the previous
+    /// one are merged into it.
+    #[inline]
+    pub fn request(&self) {
+        self.0.store(true, Ordering::Release);
+    }
+}
+
+/// The stream's side of its `KeyUpdateHandle`s.
+#[derive(Debug, Default)]
+pub(crate) struct KeyUpdate(Option<Arc<AtomicBool>>);
+
+impl KeyUpdate {
+    pub(crate) fn handle(&mut self) -> KeyUpdateHandle {
+        KeyUpdateHandle(self.0.get_or_insert_with(Default::default).clone())
+    }
+
+    /// Queues a `key_update` message if one was requested and the handshake is done.
+    pub(crate) fn apply<SD: SideData>(&self, session: &mut ConnectionCommon<SD>) -> io::Result<()> {
+        match &self.0 {
+            Some(requested)
+                if !session.is_handshaking() && requested.swap(false, Ordering::Acquire) =>
+            {
+                session.refresh_traffic_keys().map_err(|err| {
+                    // The handshake is done, so this is a TLS 1.2 connection.
+                    let kind = match err {
+                        rustls::Error::HandshakeNotComplete => io::ErrorKind::Unsupported,
+                        _ => io::ErrorKind::InvalidData,
+                    };
+                    io::Error::new(kind, err)
+                })
+            }
+            _ => Ok(()),
+        }
+    }
+}
@@ -35,7 +35,68 @@
 //! This means that if you give inconsistent data in two `poll_write`, it may cause unexpected behavior.
 //!
 //! see <https://github.com/tokio-rs/tls/issues/41>
+//!
+//! # What about TLS 1.2 renegotiation?
+//!
+//! rustls does not support renegotiation at all: a `HelloRequest` or renegotiating
+//! `ClientHello` is answered with a `no_renegotiation` warning alert. A rustls server
+//! therefore never renegotiates, and the renegotiation attack of RFC 5746 cannot be
+//! mounted against a `server::TlsStream`.
+//!
+//! A rustls client sends the renegotiation SCSV, but does not require a TLS 1.2
+//! server to answer with `renegotiation_info`. A server that does not implement
+//! RFC 5746 may still renegotiate with an attacker, who can then inject plaintext
+//! ahead of the client's requests. rustls does not expose whether the peer indicated
+//! secure renegotiation support, so neither can a `TlsStream`; prefer TLS 1.3 when
+//! the servers involved cannot be trusted to implement it.
+//!
+//! It also means that once established, a connection never returns to handshaking:
+//! `is_handshaking()` stays `false`, and `poll_write` always encrypts application
+//! data directly. Post-handshake messages such as TLS 1.3 key updates and session
+//! tickets are processed without interrupting reads or writes.
+//!
+//! # How do I tell TLS errors apart?
+//!
+//! TLS protocol errors are returned as an `io::Error` of kind `InvalidData` that wraps
+//! the `rustls::Error` itself, so the exact cause is one downcast away. For example, a
+//! record that fails its integrity check, as tampered ciphertext does, is reported as
+//! `rustls::Error::DecryptError`, and rustls answers the peer with `bad_record_mac`:
+//!
+//! ```
+//! fn is_integrity_failure(err: &std::io::Error) -> bool {
+//!     matches!(
+//!         err.get_ref().and_then(|err| err.downcast_ref::<rustls::Error>()),
+//!         Some(rustls::Error::DecryptError)
+//!     )
+//! }
+//! ```
+//!
+//! # Can a busy stream starve other tasks?
+//!
+//! Not when the IO takes part in Tokio's cooperative scheduling, as Tokio's own
+//! sockets and `DuplexStream` do. A `TlsStream` does not loop over records on its
+//! own: each `poll_read` reads from the IO only until it has some plaintext to
+//! return, plus whatever `read_batch_limit` allows. Every read from the IO spends the
+//! task's budget, so a flood of records makes the IO return `Pending` once the budget
+//! is used up, and the stream passes that on. Tokio offers no stable way for other
+//! crates to spend the budget themselves, so IO that does not take part, such as an
+//! in-memory reader that is always ready, can still keep a task busy.
+//!
+//! # Can a client certificate key live in an HSM?
+//!
+//! Yes, but signing is synchronous in rustls: `Signer::sign` is called from within
+//! `process_new_packets`, while a `TlsStream` is being polled, so there is no point at
+//! which tokio-rustls could await an asynchronous signer. Implement
+//! `rustls::client::ResolvesClientCert`, which is only consulted when the server sends
+//! a CertificateRequest, and make its `Signer` wait for the HSM.
+//!
+//! That wait blocks the thread polling the stream. On a multi-threaded runtime, wrap
+//! it in `tokio::task::block_in_place`, which moves the thread's other tasks elsewhere,
+//! and drive the HSM future with `Handle::block_on` or receive its answer from a
+//! channel fed by the HSM client. On a current-thread runtime, the HSM client must run
+//! on a thread of its own, since the runtime cannot make progress while it waits.
 
+use std::fmt;
 use std::future::Future;
 use std::io;
 #[cfg(unix)]
@@ -45,10 +106,14 @@ use std::os::windows::io::{AsRawSocket, RawSocket};
 use std::pin::Pin;
 use std::sync::Arc;
 use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
 
 pub use rustls;
 use rustls::server::AcceptedAlert;
-use rustls::{ClientConfig, ClientConnection, CommonState, ServerConfig, ServerConnection};
+use rustls::{
+    AlertDescription, ClientConfig, ClientConnection, CommonState, HandshakeKind, ProtocolVersion,
+    ServerConfig, ServerConnection,
+};
 use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
 
 macro_rules! ready {
@@ -60,10 +125,36 @@ macro_rules! ready {
     };
 }
 
+mod available;
+pub use available::DataAvailable;
+mod batch;
+pub use batch::{Batch, BatchWriter};
 pub mod client;
 mod common;
 use common::{MidHandshake, TlsState};
+mod deadline;
+use deadline::HandshakeDeadline;
+mod der;
+mod drain;
+pub use drain::{drain, Drain};
+mod early_data_budget;
+pub use early_data_budget::EarlyDataBudget;
+use early_data_budget::EarlyDataPermit;
+mod instrumented;
+pub use instrumented::{Instrumented, IoStats};
+mod interrupted;
+pub use interrupted::{HandshakeInterrupted, HandshakePhase};
+mod key_update;
+pub use key_update::KeyUpdateHandle;
+mod pinning;
+mod rate_limit;
+mod records;
+pub use records::{Direction, ObserveRecords, RecordMeta};
 pub mod server;
+mod tee;
+pub use tee::CopyWithTee;
+mod trailing;
+pub use trailing::{Downgrade, KeepTrailingData};
 
 /// A wrapper around a `rustls::ClientConfig`, providing an async `connect` method.
 #[derive(Clone)]
@@ -71,12 +162,19 @@ pub struct TlsConnector {
     inner: Arc<ClientConfig>,
     #[cfg(feature = "early-data")]
     early_data: bool,
+    #[cfg(feature = "early-data")]
+    flush_completes_handshake: bool,
+    read_batch_limit: usize,
+    max_handshake_duration: Option<Duration>,
 }
 
 /// A wrapper around a `rustls::ServerConfig`, providing an async `accept` method.
 #[derive(Clone)]
 pub struct TlsAcceptor {
     inner: Arc<ServerConfig>,
+    read_batch_limit: usize,
+    max_handshake_duration: Option<Duration>,
+    early_data_budget: Option<EarlyDataBudget>,
 }
 
 impl From<Arc<ClientConfig>> for TlsConnector {
@@ -85,13 +183,22 @@ impl From<Arc<ClientConfig>> for TlsConnector {
             inner,
             #[cfg(feature = "early-data")]
             early_data: false,
+            #[cfg(feature = "early-data")]
+            flush_completes_handshake: true,
+            read_batch_limit: 1,
+            max_handshake_duration: None,
         }
     }
 }
 
 impl From<Arc<ServerConfig>> for TlsAcceptor {
     fn from(inner: Arc<ServerConfig>) -> TlsAcceptor {
-        TlsAcceptor { inner }
+        TlsAcceptor {
+            inner,
+            read_batch_limit: 1,
+            max_handshake_duration: None,
+            early_data_budget: None,
+        }
     }
 }
 
@@ -106,6 +213,41 @@ impl TlsConnector {
         self
     }
 
+    /// How many reads from the underlying IO a single `poll_read` may make. Defaults to 1.
+    ///
+    /// Beyond the reads needed to yield some plaintext, further reads are made only while
+    /// the IO has data ready and the caller's buffer could take more than is buffered.
+    /// This suits edge-triggered or batching reactors. Reads from Tokio's own IO types
+    /// count against its cooperative budget, so a large limit cannot starve other tasks.
+    pub fn read_batch_limit(mut self, limit: usize) -> TlsConnector {
+        self.read_batch_limit = limit;
+        self
+    }
+
+    /// Caps the wall-clock time the handshake may take, across all its round trips.
+    ///
+    /// The timer starts when the handshake is first driven. If the handshake has not
+    /// completed by the deadline, it fails with `TimedOut`, however much progress it
+    /// was making; a peer cannot keep it alive with slow flights. This covers handshakes
+    /// completed by `Connect`, by the first IO on a `connect_lazy` stream, and after
+    /// 0-RTT data. Requires a Tokio runtime with the time driver enabled.
+    pub fn max_handshake_duration(mut self, limit: Duration) -> TlsConnector {
+        self.max_handshake_duration = Some(limit);
+        self
+    }
+
+    /// Whether flushing a stream that is still sending 0-RTT data completes the
+    /// handshake. Defaults to `true`.
+    ///
+    /// When `false`, `poll_flush` only writes out what is buffered so far, including the
+    /// early data. The handshake is then completed by the first read, by shutdown, or
+    /// by `poll_handshake`.
+    #[cfg(feature = "early-data")]
+    pub fn flush_completes_handshake(mut self, flag: bool) -> TlsConnector {
+        self.flush_completes_handshake = flag;
+        self
+    }
+
     #[inline]
     pub fn connect<IO>(&self, domain: pki_types::ServerName<'static>, stream: IO) -> Connect<IO>
     where
@@ -136,25 +278,129 @@ impl TlsConnector {
             }
         };
         f(&mut session);
+        self.connect_with_connection(session, stream)
+    }
 
-        Connect(MidHandshake::Handshaking(client::TlsStream {
-            io: stream,
+    /// Connects using a `ClientConnection` that was built by the caller.
+    ///
+    /// This is the place to hand over a connection created from a `ClientConfig` other
+    /// than the connector's own, such as one whose `CryptoProvider` orders cipher suites
+    /// and key exchange groups to resemble a particular client. The connector's own
+    /// settings, such as 0-RTT and `max_handshake_duration`, still apply to it.
+    pub fn connect_with_connection<IO>(&self, session: ClientConnection, stream: IO) -> Connect<IO>
+    where
+        IO: AsyncRead + AsyncWrite + Unpin,
+    {
+        Connect(MidHandshake::Handshaking(self.stream(session, stream)))
+    }
 
-            #[cfg(not(feature = "early-data"))]
-            state: TlsState::Stream,
+    /// Returns a stream without running the handshake.
+    ///
+    /// The handshake is driven by the first read, write or flush on the returned
+    /// stream, and any handshake error is returned from that call. This suits
+    /// connection pools that create connections ahead of time.
+    pub fn connect_lazy<IO>(
+        &self,
+        domain: pki_types::ServerName<'static>,
+        stream: IO,
+    ) -> io::Result<client::TlsStream<IO>>
+    where
+        IO: AsyncRead + AsyncWrite + Unpin,
+    {
+        let session = ClientConnection::new(self.inner.clone(), domain)
+            .map_err(|error| io::Error::new(io::ErrorKind::Other, error))?;
 
-            #[cfg(feature = "early-data")]
-            state: if self.early_data && session.early_data().is_some() {
-                TlsState::EarlyData(0, Vec::new())
-            } else {
-                TlsState::Stream
-            },
-            session,
-        }))
+        Ok(self.stream(session, stream))
+    }
+
+    /// Connects like `connect`, but fails unless the server selects one of `protocols`
+    /// through ALPN.
+    ///
+    /// On a mismatch, including when the server selects no protocol, the connection is
+    /// closed with `close_notify` and the future resolves to an `InvalidData` error
+    /// wrapping `rustls::Error::NoApplicationProtocol`. The protocols offered are still
+    /// those of the `ClientConfig`.
+    pub fn connect_expecting_alpn<IO>(
+        &self,
+        domain: pki_types::ServerName<'static>,
+        stream: IO,
+        protocols: &[&[u8]],
+    ) -> ConnectExpectingAlpn<IO>
+    where
+        IO: AsyncRead + AsyncWrite + Unpin,
+    {
+        ConnectExpectingAlpn {
+            connect: self.connect(domain, stream),
+            protocols: protocols.iter().map(|p| p.to_vec()).collect(),
+            stream: None,
+            rejected: false,
+        }
+    }
+
+    /// Wraps `session`, applying this connector's settings.
+    fn stream<IO>(&self, mut session: ClientConnection, io: IO) -> client::TlsStream<IO> {
+        let state = self.initial_state(&mut session);
+        let mut stream = client::TlsStream::new(io, session, state);
+        #[cfg(feature = "early-data")]
+        {
+            stream.flush_completes_handshake = self.flush_completes_handshake;
+        }
+        stream.read_batch_limit = self.read_batch_limit;
+        stream.handshake_deadline = HandshakeDeadline::new(self.max_handshake_duration);
+        stream.crypto_provider = Some(self.inner.crypto_provider().clone());
+        stream
+    }
+
+    #[cfg(not(feature = "early-data"))]
+    #[inline]
+    fn initial_state(&self, _: &mut ClientConnection) -> TlsState {
+        TlsState::Stream
+    }
+
+    #[cfg(feature = "early-data")]
+    #[inline]
+    fn initial_state(&self, session: &mut ClientConnection) -> TlsState {
+        if self.early_data && session.early_data().is_some() {
+            TlsState::EarlyData(0, Vec::new())
+        } else {
+            TlsState::Stream
+        }
     }
 }
 
 impl TlsAcceptor {
+    /// How many reads from the underlying IO a single `poll_read` may make. Defaults to 1.
+    ///
+    /// See `TlsConnector::read_batch_limit`. For a [`LazyConfigAcceptor`], this applies
+    /// through `StartHandshake::into_stream_with_acceptor` but not through
+    /// `StartHandshake::into_stream`.
+    pub fn read_batch_limit(mut self, limit: usize) -> TlsAcceptor {
+        self.read_batch_limit = limit;
+        self
+    }
+
+    /// Caps the wall-clock time the handshake may take, across all its round trips.
+    ///
+    /// See `TlsConnector::max_handshake_duration`. For a [`LazyConfigAcceptor`], this
+    /// applies through `StartHandshake::into_stream_with_acceptor`, from the time the
+    /// stream is first polled, but not through `StartHandshake::into_stream`.
+    pub fn max_handshake_duration(mut self, limit: Duration) -> TlsAcceptor {
+        self.max_handshake_duration = Some(limit);
+        self
+    }
+
+    /// Shares `budget` between the connections this acceptor accepts, rejecting 0-RTT
+    /// once it is used up.
+    ///
+    /// See [`EarlyDataBudget`]. This has no effect unless
+    /// `ServerConfig::max_early_data_size` is set. For a [`LazyConfigAcceptor`], this
+    /// applies through `StartHandshake::into_stream_with_acceptor` but not through
+    /// `StartHandshake::into_stream`.
+    pub fn early_data_budget(mut self, budget: EarlyDataBudget) -> TlsAcceptor {
+        self.early_data_budget = Some(budget);
+        self
+    }
+
     #[inline]
     pub fn accept<IO>(&self, stream: IO) -> Accept<IO>
     where
@@ -179,20 +425,54 @@ impl TlsAcceptor {
                 });
             }
         };
+        let early_data_permit = match self.acquire_early_data() {
+            Ok(permit) => permit,
+            Err(()) => {
+                session.reject_early_data();
+                None
+            }
+        };
         f(&mut session);
 
-        Accept(MidHandshake::Handshaking(server::TlsStream {
-            session,
-            io: stream,
-            state: TlsState::Stream,
-        }))
+        let mut stream = server::TlsStream::new(stream, session);
+        self.configure(&mut stream);
+        stream.early_data_permit = early_data_permit;
+        Accept(MidHandshake::Handshaking(stream))
+    }
+
+    /// Takes a slot from the early data budget, if there is one, for a new connection.
+    ///
+    /// Fails once the budget is used up, when the connection must reject 0-RTT.
+    fn acquire_early_data(&self) -> Result<Option<EarlyDataPermit>, ()> {
+        match &self.early_data_budget {
+            Some(budget) if self.inner.max_early_data_size > 0 => {
+                budget.try_acquire().map(Some).ok_or(())
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Applies this acceptor's settings to `stream`.
+    fn configure<IO>(&self, stream: &mut server::TlsStream<IO>) {
+        stream.read_batch_limit = self.read_batch_limit;
+        stream.handshake_deadline = HandshakeDeadline::new(self.max_handshake_duration);
     }
 }
 
+/// A future that reads a ClientHello from `IO` and resolves to a [`StartHandshake`],
+/// allowing the `ServerConfig` to be chosen based on its contents.
+///
+/// Bytes read from `IO` are buffered inside the wrapped `rustls::server::Acceptor` until
+/// a complete ClientHello has been received. `LazyConfigAcceptor` is `Send` whenever
+/// `IO` is, so a partially-read handshake may be moved to another task or thread
+/// between polls without losing those buffered bytes. The same holds for the
+/// resulting [`StartHandshake`].
 pub struct LazyConfigAcceptor<IO> {
     acceptor: rustls::server::Acceptor,
     io: Option<IO>,
     alert: Option<(rustls::Error, AcceptedAlert)>,
+    received_data: bool,
+    recorded: Option<Vec<u8>>,
 }
 
 impl<IO> LazyConfigAcceptor<IO>
@@ -205,9 +485,52 @@ where
             acceptor,
             io: Some(io),
             alert: None,
+            received_data: false,
+            recorded: None,
         }
     }
 
+    /// Keeps a copy of the bytes read from the client, so that the raw ClientHello is
+    /// available from `StartHandshake::raw_client_hello`, for example for JA3 or JA4
+    /// fingerprinting.
+    ///
+    /// To inspect the ClientHello sent by a `TlsConnector`, wrap its IO instead: the
+    /// first records written to it carry the ClientHello.
+    pub fn record_client_hello(mut self, flag: bool) -> Self {
+        self.recorded = if flag { Some(Vec::new()) } else { None };
+        self
+    }
+
+    /// Returns `true` once any bytes have been read from the client.
+    ///
+    /// Combined with a timeout, this tells a peer that never started the handshake
+    /// (such as an idle scanner) apart from one that sent an incomplete ClientHello.
+    /// A peer that closes the connection before completing its ClientHello fails the
+    /// acceptor with `io::ErrorKind::UnexpectedEof`, while a malformed ClientHello
+    /// fails it with `io::ErrorKind::InvalidData`.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # async fn listen(stream: tokio::net::TcpStream) {
+    /// use std::time::Duration;
+    ///
+    /// let mut acceptor =
+    ///     tokio_rustls::LazyConfigAcceptor::new(rustls::server::Acceptor::default(), stream);
+    ///
+    /// match tokio::time::timeout(Duration::from_secs(10), &mut acceptor).await {
+    ///     Ok(Ok(_start)) => { /* choose a config and continue the handshake */ }
+    ///     Ok(Err(_malformed_or_closed)) => {}
+    ///     Err(_) if !acceptor.has_received_data() => { /* idle connection */ }
+    ///     Err(_) => { /* slow or incomplete ClientHello */ }
+    /// }
+    /// # }
+    /// ```
+    #[inline]
+    pub fn has_received_data(&self) -> bool {
+        self.received_data
+    }
+
     /// Takes back the client connection. Will return `None` if called more than once or if the
     /// connection has been accepted.
     ///
@@ -290,9 +613,16 @@ where
             }
 
             let mut reader = common::SyncReadAdapter { io, cx };
-            match this.acceptor.read_tls(&mut reader) {
+            let read = match this.recorded.as_mut() {
+                Some(buf) => this.acceptor.read_tls(&mut Recorder {
+                    inner: &mut reader,
+                    buf,
+                }),
+                None => this.acceptor.read_tls(&mut reader),
+            };
+            match read {
                 Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()).into(),
-                Ok(_) => {}
+                Ok(_) => this.received_data = true,
                 Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Poll::Pending,
                 Err(e) => return Err(e).into(),
             }
@@ -300,7 +630,15 @@ where
             match this.acceptor.accept() {
                 Ok(Some(accepted)) => {
                     let io = this.io.take().unwrap();
-                    return Poll::Ready(Ok(StartHandshake { accepted, io }));
+                    let raw_client_hello = this
+                        .recorded
+                        .take()
+                        .and_then(|records| first_handshake_message(&records));
+                    return Poll::Ready(Ok(StartHandshake {
+                        accepted,
+                        io,
+                        raw_client_hello,
+                    }));
                 }
                 Ok(None) => {}
                 Err((err, alert)) => {
@@ -311,9 +649,49 @@ where
     }
 }
 
+/// Copies everything read through it into `buf`.
+struct Recorder<'a, R> {
+    inner: R,
+    buf: &'a mut Vec<u8>,
+}
+
+impl<'a, R: io::Read> io::Read for Recorder<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.buf.extend_from_slice(&buf[..n]);
+        Ok(n)
+    }
+}
+
+/// Reassembles the first handshake message from the plaintext records at the start
+/// of `records`.
+fn first_handshake_message(mut records: &[u8]) -> Option<Vec<u8>> {
+    const HANDSHAKE: u8 = 0x16;
+
+    let mut message = Vec::new();
+    while records.len() >= 5 && records[0] == HANDSHAKE {
+        let len = usize::from(u16::from_be_bytes([records[3], records[4]]));
+        message.extend_from_slice(records.get(5..5 + len)?);
+        records = &records[5 + len..];
+
+        if message.len() >= 4 {
+            let len = 4 + u32::from_be_bytes([0, message[1], message[2], message[3]]) as usize;
+            if message.len() >= len {
+                message.truncate(len);
+                return Some(message);
+            }
+        }
+    }
+
+    None
+}
+
+/// A ClientHello that has been read by a [`LazyConfigAcceptor`], ready to continue
+/// the handshake once a `ServerConfig` has been chosen.
 pub struct StartHandshake<IO> {
     accepted: rustls::server::Accepted,
     io: IO,
+    raw_client_hello: Option<Vec<u8>>,
 }
 
 impl<IO> StartHandshake<IO>
@@ -324,6 +702,15 @@ where
         self.accepted.client_hello()
     }
 
+    /// The ClientHello handshake message exactly as the client sent it, starting with
+    /// its 4-byte handshake header and reassembled from its records.
+    ///
+    /// Returns `None` unless `LazyConfigAcceptor::record_client_hello` was enabled.
+    #[inline]
+    pub fn raw_client_hello(&self) -> Option<&[u8]> {
+        self.raw_client_hello.as_deref()
+    }
+
     pub fn into_stream(self, config: Arc<ServerConfig>) -> Accept<IO> {
         self.into_stream_with(config, |_| ())
     }
@@ -346,11 +733,86 @@ where
         };
         f(&mut conn);
 
-        Accept(MidHandshake::Handshaking(server::TlsStream {
-            session: conn,
-            io: self.io,
-            state: TlsState::Stream,
-        }))
+        Accept(MidHandshake::Handshaking(server::TlsStream::new(
+            self.io, conn,
+        )))
+    }
+
+    /// Like `into_stream_with`, taking the `ServerConfig` from `acceptor` and applying
+    /// its settings, such as `TlsAcceptor::max_handshake_duration`, to the stream.
+    pub fn into_stream_with_acceptor<F>(self, acceptor: &TlsAcceptor, f: F) -> Accept<IO>
+    where
+        F: FnOnce(&mut ServerConnection),
+    {
+        // The session decides whether to take early data as it processes the ClientHello,
+        // so with the budget used up it needs a config that never offers it. Tickets
+        // issued on this connection then carry no early data allowance either.
+        let (config, early_data_permit) = match acceptor.acquire_early_data() {
+            Ok(permit) => (acceptor.inner.clone(), permit),
+            Err(()) => {
+                let mut config = ServerConfig::clone(&acceptor.inner);
+                config.max_early_data_size = 0;
+                (Arc::new(config), None)
+            }
+        };
+
+        let mut accept = self.into_stream_with(config, f);
+        if let MidHandshake::Handshaking(stream) = &mut accept.0 {
+            acceptor.configure(stream);
+            stream.early_data_permit = early_data_permit;
+        }
+        accept
+    }
+
+    /// Rejects the ClientHello by sending the client a fatal alert, for example
+    /// `AlertDescription::UnrecognisedName` when no `ServerConfig` matches its SNI.
+    ///
+    /// The returned future resolves to the underlying IO once the alert has been
+    /// written and flushed, leaving it to the caller to close the connection.
+    pub fn reject(self, alert: AlertDescription) -> Reject<IO> {
+        Reject {
+            io: Some(self.io),
+            // A plaintext alert record: type, legacy version, length, level, description
+            record: [0x15, 0x03, 0x03, 0x00, 0x02, 0x02, u8::from(alert)],
+            pos: 0,
+        }
+    }
+}
+
+/// Future returned from [`StartHandshake::reject`].
+pub struct Reject<IO> {
+    io: Option<IO>,
+    record: [u8; 7],
+    pos: usize,
+}
+
+impl<IO> Future for Reject<IO>
+where
+    IO: AsyncWrite + Unpin,
+{
+    type Output = io::Result<IO>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let io = match this.io.as_mut() {
+            Some(io) => io,
+            None => {
+                return Poll::Ready(Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "reject cannot be polled after completion",
+                )))
+            }
+        };
+
+        while this.pos < this.record.len() {
+            match ready!(Pin::new(&mut *io).poll_write(cx, &this.record[this.pos..]))? {
+                0 => return Poll::Ready(Err(io::ErrorKind::WriteZero.into())),
+                n => this.pos += n,
+            }
+        }
+
+        ready!(Pin::new(&mut *io).poll_flush(cx))?;
+        Poll::Ready(Ok(this.io.take().unwrap()))
     }
 }
 
@@ -374,6 +836,19 @@ impl<IO> Connect<IO> {
         FallibleConnect(self.0)
     }
 
+    /// Runs `f` once the handshake completes; see `client::TlsStream::on_established`.
+    ///
+    /// Nothing is run if the handshake fails.
+    pub fn on_established(
+        mut self,
+        f: impl FnOnce(HandshakeSummary) + Send + Sync + 'static,
+    ) -> Self {
+        if let MidHandshake::Handshaking(sess) = &mut self.0 {
+            sess.on_established(f);
+        }
+        self
+    }
+
     pub fn get_ref(&self) -> Option<&IO> {
         match &self.0 {
             MidHandshake::Handshaking(sess) => Some(sess.get_ref().0),
@@ -391,6 +866,21 @@ impl<IO> Connect<IO> {
             MidHandshake::End => None,
         }
     }
+
+    /// Stops the handshake and returns the underlying IO, for example to fall back to
+    /// another protocol on the same connection.
+    ///
+    /// Handshake bytes already exchanged with the peer are not taken back, and any TLS
+    /// data still buffered in the session is discarded. Returns `None` if the handshake
+    /// has already completed.
+    pub fn abort(self) -> Option<IO> {
+        match self.0 {
+            MidHandshake::Handshaking(sess) => Some(sess.io),
+            MidHandshake::SendAlert { io, .. } => Some(io),
+            MidHandshake::Error { io, .. } => Some(io),
+            MidHandshake::End => None,
+        }
+    }
 }
 
 impl<IO> Accept<IO> {
@@ -399,6 +889,19 @@ impl<IO> Accept<IO> {
         FallibleAccept(self.0)
     }
 
+    /// Runs `f` once the handshake completes; see `server::TlsStream::on_established`.
+    ///
+    /// Nothing is run if the handshake fails.
+    pub fn on_established(
+        mut self,
+        f: impl FnOnce(HandshakeSummary) + Send + Sync + 'static,
+    ) -> Self {
+        if let MidHandshake::Handshaking(sess) = &mut self.0 {
+            sess.on_established(f);
+        }
+        self
+    }
+
     pub fn get_ref(&self) -> Option<&IO> {
         match &self.0 {
             MidHandshake::Handshaking(sess) => Some(sess.get_ref().0),
@@ -416,6 +919,21 @@ impl<IO> Accept<IO> {
             MidHandshake::End => None,
         }
     }
+
+    /// Stops the handshake and returns the underlying IO, for example to fall back to
+    /// another protocol on the same connection.
+    ///
+    /// Handshake bytes already exchanged with the peer are not taken back, and any TLS
+    /// data still buffered in the session is discarded. Returns `None` if the handshake
+    /// has already completed.
+    pub fn abort(self) -> Option<IO> {
+        match self.0 {
+            MidHandshake::Handshaking(sess) => Some(sess.io),
+            MidHandshake::SendAlert { io, .. } => Some(io),
+            MidHandshake::Error { io, .. } => Some(io),
+            MidHandshake::End => None,
+        }
+    }
 }
 
 impl<IO: AsyncRead + AsyncWrite + Unpin> Future for Connect<IO> {
@@ -427,6 +945,47 @@ impl<IO: AsyncRead + AsyncWrite + Unpin> Future for Connect<IO> {
     }
 }
 
+/// Future returned from `TlsConnector::connect_expecting_alpn()`.
+pub struct ConnectExpectingAlpn<IO> {
+    connect: Connect<IO>,
+    protocols: Vec<Vec<u8>>,
+    stream: Option<client::TlsStream<IO>>,
+    rejected: bool,
+}
+
+impl<IO: AsyncRead + AsyncWrite + Unpin> Future for ConnectExpectingAlpn<IO> {
+    type Output = io::Result<client::TlsStream<IO>>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let stream = match &mut this.stream {
+            Some(stream) => stream,
+            None => this
+                .stream
+                .insert(ready!(Pin::new(&mut this.connect).poll(cx))?),
+        };
+
+        if !this.rejected {
+            // With 0-RTT, `Connect` resolves before the server has chosen a protocol.
+            ready!(stream.poll_handshake(cx))?;
+
+            let alpn = stream.get_ref().1.alpn_protocol();
+            if alpn.map_or(false, |alpn| this.protocols.iter().any(|p| p == alpn)) {
+                return Poll::Ready(Ok(this.stream.take().unwrap()));
+            }
+            this.rejected = true;
+        }
+
+        // A failure to close would only hide the mismatch.
+        let _ = ready!(Pin::new(stream).poll_shutdown(cx));
+        this.stream = None;
+        Poll::Ready(Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            rustls::Error::NoApplicationProtocol,
+        )))
+    }
+}
+
 impl<IO: AsyncRead + AsyncWrite + Unpin> Future for Accept<IO> {
     type Output = io::Result<server::TlsStream<IO>>;
 
@@ -493,6 +1052,339 @@ impl<T> TlsStream<T> {
             }
         }
     }
+
+    /// See `client::TlsStream::key_update_handle`.
+    #[inline]
+    pub fn key_update_handle(&mut self) -> KeyUpdateHandle {
+        match self {
+            TlsStream::Client(io) => io.key_update_handle(),
+            TlsStream::Server(io) => io.key_update_handle(),
+        }
+    }
+
+    /// See `client::TlsStream::is_early_data_phase`. Always `false` for a server.
+    #[inline]
+    pub fn is_early_data_phase(&self) -> bool {
+        match self {
+            TlsStream::Client(io) => io.is_early_data_phase(),
+            TlsStream::Server(_) => false,
+        }
+    }
+
+    /// See `client::TlsStream::connection_id`.
+    #[inline]
+    pub fn connection_id(&self) -> u64 {
+        match self {
+            TlsStream::Client(io) => io.connection_id(),
+            TlsStream::Server(io) => io.connection_id(),
+        }
+    }
+
+    /// See `client::TlsStream::on_established`.
+    pub fn on_established(&mut self, f: impl FnOnce(HandshakeSummary) + Send + Sync + 'static) {
+        match self {
+            TlsStream::Client(io) => io.on_established(f),
+            TlsStream::Server(io) => io.on_established(f),
+        }
+    }
+
+    /// See `client::TlsStream::handshake_summary`.
+    #[inline]
+    pub fn handshake_summary(&self) -> HandshakeSummary {
+        HandshakeSummary::new(self.get_ref().1)
+    }
+
+    /// See `client::TlsStream::auth_and_resumption`.
+    #[inline]
+    pub fn auth_and_resumption(&self) -> Option<HandshakeProfile> {
+        HandshakeProfile::new(self.get_ref().1)
+    }
+
+    /// See `client::TlsStream::key_exchange_group_name`.
+    #[inline]
+    pub fn key_exchange_group_name(&self) -> Option<&'static str> {
+        common::key_exchange_group_name(self.get_ref().1)
+    }
+
+    /// See `client::TlsStream::negotiated_http_version`.
+    #[inline]
+    pub fn negotiated_http_version(&self) -> Option<HttpVersion> {
+        common::negotiated_http_version(self.get_ref().1)
+    }
+
+    /// See `client::TlsStream::alpn_was_preferred`.
+    #[inline]
+    pub fn alpn_was_preferred(&self, preferred: &[u8]) -> bool {
+        self.get_ref().1.alpn_protocol() == Some(preferred)
+    }
+
+    /// Whether the read side has reached EOF.
+    ///
+    /// See `client::TlsStream::is_eof`.
+    #[inline]
+    pub fn is_eof(&self) -> bool {
+        match self {
+            TlsStream::Client(io) => io.is_eof(),
+            TlsStream::Server(io) => io.is_eof(),
+        }
+    }
+
+    /// See `client::TlsStream::set_read_rate_limit`.
+    #[inline]
+    pub fn set_read_rate_limit(&mut self, limit: Option<std::num::NonZeroU64>) {
+        match self {
+            TlsStream::Client(io) => io.set_read_rate_limit(limit),
+            TlsStream::Server(io) => io.set_read_rate_limit(limit),
+        }
+    }
+
+    /// See `client::TlsStream::set_read_batch_limit`.
+    #[inline]
+    pub fn set_read_batch_limit(&mut self, limit: usize) {
+        match self {
+            TlsStream::Client(io) => io.set_read_batch_limit(limit),
+            TlsStream::Server(io) => io.set_read_batch_limit(limit),
+        }
+    }
+
+    /// See `client::TlsStream::handshake_completed_at`.
+    #[inline]
+    pub fn handshake_completed_at(&self) -> Option<Instant> {
+        match self {
+            TlsStream::Client(io) => io.handshake_completed_at(),
+            TlsStream::Server(io) => io.handshake_completed_at(),
+        }
+    }
+
+    /// See `client::TlsStream::wants_read`.
+    #[inline]
+    pub fn wants_read(&self) -> bool {
+        self.get_ref().1.wants_read()
+    }
+
+    /// See `client::TlsStream::wants_write`.
+    #[inline]
+    pub fn wants_write(&self) -> bool {
+        self.get_ref().1.wants_write()
+    }
+
+    /// See `client::TlsStream::buffered_bytes`.
+    #[inline]
+    pub fn buffered_bytes(&mut self) -> io::Result<(usize, usize)> {
+        match self {
+            TlsStream::Client(io) => io.buffered_bytes(),
+            TlsStream::Server(io) => io.buffered_bytes(),
+        }
+    }
+
+    /// See `client::TlsStream::memory_usage`.
+    #[inline]
+    pub fn memory_usage(&mut self) -> io::Result<usize> {
+        match self {
+            TlsStream::Client(io) => io.memory_usage(),
+            TlsStream::Server(io) => io.memory_usage(),
+        }
+    }
+}
+
+impl<T> TlsStream<T>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    /// Drives any outstanding handshake to completion, without reading or writing
+    /// application data.
+    ///
+    /// See [`client::TlsStream::poll_handshake`] and [`server::TlsStream::poll_handshake`].
+    #[inline]
+    pub fn poll_handshake(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self {
+            TlsStream::Client(x) => x.poll_handshake(cx),
+            TlsStream::Server(x) => x.poll_handshake(cx),
+        }
+    }
+
+    /// See `client::TlsStream::data_available`.
+    #[inline]
+    pub fn data_available(&mut self) -> DataAvailable<'_, Self> {
+        DataAvailable::new(self)
+    }
+
+    /// See `client::TlsStream::batch`.
+    #[inline]
+    pub fn batch<F, R>(&mut self, f: F) -> Batch<'_, Self, R>
+    where
+        F: FnOnce(&mut BatchWriter) -> io::Result<R>,
+    {
+        Batch::new(self, f)
+    }
+
+    /// See `client::TlsStream::copy_with_tee`.
+    #[inline]
+    pub fn copy_with_tee<'a, W, U>(
+        &'a mut self,
+        dst: &'a mut W,
+        tee: &'a mut U,
+    ) -> CopyWithTee<'a, Self, W, U>
+    where
+        W: AsyncWrite + Unpin,
+        U: AsyncWrite + Unpin,
+    {
+        CopyWithTee::new(self, dst, tee)
+    }
+}
+
+/// A summary of the parameters negotiated during the handshake, for logging and telemetry.
+///
+/// Implements `serde::Serialize` when the `serde` feature is enabled. Every field is
+/// `None` while the handshake is still in progress.
+#[non_exhaustive]
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct HandshakeSummary {
+    /// The negotiated protocol version, e.g. `"TLSv1_3"`.
+    pub protocol_version: Option<&'static str>,
+    /// The negotiated cipher suite, e.g. `"TLS13_AES_128_GCM_SHA256"`.
+    pub cipher_suite: Option<&'static str>,
+    /// The negotiated key exchange group, e.g. `"X25519"`.
+    pub key_exchange_group: Option<&'static str>,
+    /// The negotiated ALPN protocol, lossily converted to UTF-8.
+    pub alpn_protocol: Option<String>,
+    /// Which sort of handshake happened: `"full"`, `"full_with_hello_retry_request"`
+    /// or `"resumed"`.
+    pub handshake_kind: Option<&'static str>,
+    /// The number of certificates presented by the peer.
+    pub peer_certificates: usize,
+    /// The DER-encoded subject Name of the peer's end-entity certificate, left to the
+    /// caller to decode.
+    pub peer_subject: Option<Vec<u8>>,
+}
+
+impl HandshakeSummary {
+    pub(crate) fn new(state: &CommonState) -> Self {
+        Self {
+            protocol_version: state.protocol_version().and_then(|v| v.as_str()),
+            cipher_suite: state
+                .negotiated_cipher_suite()
+                .and_then(|suite| suite.suite().as_str()),
+            key_exchange_group: state
+                .negotiated_key_exchange_group()
+                .and_then(|group| group.name().as_str()),
+            alpn_protocol: state
+                .alpn_protocol()
+                .map(|proto| String::from_utf8_lossy(proto).into_owned()),
+            handshake_kind: state.handshake_kind().map(|kind| match kind {
+                HandshakeKind::Full => "full",
+                HandshakeKind::FullWithHelloRetryRequest => "full_with_hello_retry_request",
+                HandshakeKind::Resumed => "resumed",
+            }),
+            peer_certificates: state.peer_certificates().map_or(0, |certs| certs.len()),
+            peer_subject: state
+                .peer_certificates()
+                .and_then(|certs| certs.first())
+                .and_then(|cert| der::subject(cert))
+                .map(<[u8]>::to_vec),
+        }
+    }
+}
+
+/// A callback to run once the handshake completes; see `TlsStream::on_established`.
+#[derive(Default)]
+pub(crate) struct OnEstablished(Option<Box<dyn FnOnce(HandshakeSummary) + Send + Sync>>);
+
+impl OnEstablished {
+    /// Registers the callback, running it right away if the handshake has `completed`.
+    #[inline]
+    pub(crate) fn set(
+        &mut self,
+        f: Box<dyn FnOnce(HandshakeSummary) + Send + Sync>,
+        completed: bool,
+        state: &CommonState,
+    ) {
+        self.0 = Some(f);
+        if completed {
+            self.fire(state);
+        }
+    }
+
+    /// Runs the callback, if one is registered and has not run yet.
+    #[inline]
+    pub(crate) fn fire(&mut self, state: &CommonState) {
+        if let Some(f) = self.0.take() {
+            f(HandshakeSummary::new(state));
+        }
+    }
+}
+
+impl fmt::Debug for OnEstablished {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("OnEstablished")
+            .field(&self.0.as_ref().map(|_| ".."))
+            .finish()
+    }
+}
+
+/// How a connection was authenticated and whether it resumed an earlier session; see
+/// `TlsStream::auth_and_resumption`.
+///
+/// rustls does not support external PSKs or raw public keys, so there are no variants
+/// for them yet.
+#[non_exhaustive]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HandshakeProfile {
+    /// A full handshake, with the server authenticated by its certificate, with or
+    /// without a HelloRetryRequest.
+    FullCertificate,
+    /// A TLS 1.3 handshake resumed from a session ticket, using the resumption PSK it
+    /// carries.
+    ResumedTicket,
+    /// A resumed TLS 1.2 session. rustls does not report whether it was found by
+    /// session ID or by ticket.
+    ResumedSession,
+}
+
+impl HandshakeProfile {
+    pub(crate) fn new(state: &CommonState) -> Option<Self> {
+        Some(match state.handshake_kind()? {
+            HandshakeKind::Full | HandshakeKind::FullWithHelloRetryRequest => Self::FullCertificate,
+            HandshakeKind::Resumed => match state.protocol_version()? {
+                ProtocolVersion::TLSv1_3 => Self::ResumedTicket,
+                _ => Self::ResumedSession,
+            },
+        })
+    }
+}
+
+/// An HTTP version that can be negotiated through ALPN.
+#[non_exhaustive]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HttpVersion {
+    /// `http/1.0`
+    Http10,
+    /// `http/1.1`
+    Http11,
+    /// `h2`
+    Http2,
+}
+
+impl HttpVersion {
+    /// Maps an ALPN protocol identifier to the HTTP version it names.
+    pub fn from_alpn(protocol: &[u8]) -> Option<Self> {
+        match protocol {
+            b"http/1.0" => Some(Self::Http10),
+            b"http/1.1" => Some(Self::Http11),
+            b"h2" => Some(Self::Http2),
+            _ => None,
+        }
+    }
+
+    /// The ALPN protocol identifier for this HTTP version.
+    pub fn alpn_id(self) -> &'static [u8] {
+        match self {
+            Self::Http10 => b"http/1.0",
+            Self::Http11 => b"http/1.1",
+            Self::Http2 => b"h2",
+        }
+    }
 }
 
 impl<T> From<client::TlsStream<T>> for TlsStream<T> {
@@ -0,0 +1,35 @@
+use rustls::crypto::hash::HashAlgorithm;
+use rustls::crypto::CryptoProvider;
+
+use crate::der::{element, tbs_certificate};
+
+/// Returns the SHA-256 hash of the DER-encoded SubjectPublicKeyInfo of `cert`, as
+/// used for public key pinning (RFC 7469).
+///
+/// The hash is taken from the first TLS 1.3 cipher suite of `provider` that uses
+/// SHA-256, as RFC 8446 requires TLS13_AES_128_GCM_SHA256 to be implemented.
+pub(crate) fn spki_sha256(provider: &CryptoProvider, cert: &[u8]) -> Option<[u8; 32]> {
+    let hash_provider = provider
+        .cipher_suites
+        .iter()
+        .filter_map(|suite| suite.tls13())
+        .map(|suite| suite.common.hash_provider)
+        .find(|hash| hash.algorithm() == HashAlgorithm::SHA256)?;
+
+    let hash = hash_provider.hash(spki(cert)?);
+    let mut out = [0; 32];
+    out.copy_from_slice(hash.as_ref());
+    Some(out)
+}
+
+/// Finds the SubjectPublicKeyInfo element, header included, in a DER certificate.
+fn spki(cert: &[u8]) -> Option<&[u8]> {
+    let mut tbs = tbs_certificate(cert)?;
+
+    // serialNumber, signature, issuer, validity, subject
+    for _ in 0..5 {
+        tbs = element(tbs)?.2;
+    }
+
+    element(tbs).map(|(spki, _, _)| spki)
+}
@@ -0,0 +1,82 @@
+use std::future::Future;
+use std::io;
+use std::num::NonZeroU64;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use tokio::io::ReadBuf;
+use tokio::time::{Instant, Sleep};
+
+/// A token bucket pacing reads, holding up to one second's worth of bytes.
+///
+/// A read takes no more than the bytes available, rounded up to a whole byte; the
+/// next read then waits until the bucket has refilled.
+#[derive(Debug)]
+pub(crate) struct ReadRateLimit {
+    rate: f64,
+    tokens: f64,
+    last: Instant,
+    sleep: Option<Pin<Box<Sleep>>>,
+}
+
+impl ReadRateLimit {
+    pub(crate) fn new(rate: NonZeroU64) -> Self {
+        let rate = rate.get() as f64;
+        ReadRateLimit {
+            rate,
+            tokens: rate,
+            last: Instant::now(),
+            sleep: None,
+        }
+    }
+
+    /// Waits until reading is allowed again.
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        loop {
+            let now = Instant::now();
+            let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+            self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+            self.last = now;
+
+            if self.tokens > 0.0 {
+                return Poll::Ready(());
+            }
+
+            let deadline = now + Duration::from_secs_f64((1.0 - self.tokens) / self.rate);
+            let sleep = match &mut self.sleep {
+                Some(sleep) => {
+                    sleep.as_mut().reset(deadline);
+                    sleep
+                }
+                None => self
+                    .sleep
+                    .insert(Box::pin(tokio::time::sleep_until(deadline))),
+            };
+            ready!(sleep.as_mut().poll(cx));
+        }
+    }
+
+    /// Waits until reading is allowed, then reads through `read` into at most as many
+    /// bytes of `buf` as there are tokens.
+    pub(crate) fn poll_read<F>(
+        &mut self,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+        read: F,
+    ) -> Poll<io::Result<()>>
+    where
+        F: FnOnce(&mut Context<'_>, &mut ReadBuf<'_>) -> Poll<io::Result<()>>,
+    {
+        ready!(self.poll_ready(cx));
+
+        let allowed = (self.tokens.ceil() as usize).min(buf.remaining());
+        buf.initialize_unfilled_to(allowed);
+        let mut capped = buf.take(allowed);
+        let output = read(cx, &mut capped);
+        let n = capped.filled().len();
+        buf.advance(n);
+        self.tokens -= n as f64;
+        output
+    }
+}
@@ -0,0 +1,193 @@
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use rustls::ContentType;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// Metadata of a TLS record seen by [`ObserveRecords`].
+#[non_exhaustive]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RecordMeta {
+    /// The outer content type. In TLS 1.3, everything after the ServerHello is sent as
+    /// `ApplicationData`.
+    pub content_type: ContentType,
+    /// The length of the record payload, excluding its 5-byte header.
+    pub length: u16,
+    pub direction: Direction,
+}
+
+/// Whether a record was received from or sent to the peer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    Read,
+    Write,
+}
+
+/// Wraps the IO under a TLS stream, reporting the header of every record passing
+/// through to an observer.
+///
+/// The observer sees only what is on the wire: record types and lengths, never the
+/// plaintext. A record is reported as soon as its header has been read or written.
+///
+/// ```no_run
+/// # async fn connect(stream: tokio::net::TcpStream, connector: tokio_rustls::TlsConnector) {
+/// use std::sync::Arc;
+/// use tokio_rustls::ObserveRecords;
+///
+/// let stream = ObserveRecords::new(stream, Arc::new(|meta| println!("{:?}", meta)));
+/// let domain = pki_types::ServerName::try_from("example.com").unwrap();
+/// let tls = connector.connect(domain, stream).await;
+/// # }
+/// ```
+pub struct ObserveRecords<IO> {
+    io: IO,
+    observer: Arc<dyn Fn(RecordMeta) + Send + Sync>,
+    read: Scanner,
+    write: Scanner,
+}
+
+impl<IO> ObserveRecords<IO> {
+    #[inline]
+    pub fn new(io: IO, observer: Arc<dyn Fn(RecordMeta) + Send + Sync>) -> Self {
+        ObserveRecords {
+            io,
+            observer,
+            read: Scanner::default(),
+            write: Scanner::default(),
+        }
+    }
+
+    #[inline]
+    pub fn get_ref(&self) -> &IO {
+        &self.io
+    }
+
+    #[inline]
+    pub fn get_mut(&mut self) -> &mut IO {
+        &mut self.io
+    }
+
+    #[inline]
+    pub fn into_inner(self) -> IO {
+        self.io
+    }
+}
+
+impl<IO: AsyncRead + Unpin> AsyncRead for ObserveRecords<IO> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let prev = buf.filled().len();
+        ready!(Pin::new(&mut this.io).poll_read(cx, buf))?;
+
+        let observer = &*this.observer;
+        this.read
+            .scan(&buf.filled()[prev..], |content_type, length| {
+                observer(RecordMeta {
+                    content_type,
+                    length,
+                    direction: Direction::Read,
+                })
+            });
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<IO: AsyncWrite + Unpin> AsyncWrite for ObserveRecords<IO> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let n = ready!(Pin::new(&mut this.io).poll_write(cx, buf))?;
+        this.scan_written(&[io::IoSlice::new(buf)], n);
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let n = ready!(Pin::new(&mut this.io).poll_write_vectored(cx, bufs))?;
+        this.scan_written(bufs, n);
+        Poll::Ready(Ok(n))
+    }
+
+    #[inline]
+    fn is_write_vectored(&self) -> bool {
+        self.io.is_write_vectored()
+    }
+
+    #[inline]
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().io).poll_flush(cx)
+    }
+
+    #[inline]
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().io).poll_shutdown(cx)
+    }
+}
+
+impl<IO> ObserveRecords<IO> {
+    /// Scans the first `n` bytes of `bufs`, which is what the IO accepted.
+    fn scan_written(&mut self, bufs: &[io::IoSlice<'_>], mut n: usize) {
+        let observer = &*self.observer;
+        for buf in bufs {
+            if n == 0 {
+                break;
+            }
+
+            let len = n.min(buf.len());
+            self.write.scan(&buf[..len], |content_type, length| {
+                observer(RecordMeta {
+                    content_type,
+                    length,
+                    direction: Direction::Write,
+                })
+            });
+            n -= len;
+        }
+    }
+}
+
+/// Finds record headers in a byte stream that may split them anywhere.
+#[derive(Default)]
+struct Scanner {
+    header: [u8; 5],
+    header_len: usize,
+    body_remaining: usize,
+}
+
+impl Scanner {
+    fn scan(&mut self, mut data: &[u8], mut f: impl FnMut(ContentType, u16)) {
+        while !data.is_empty() {
+            if self.body_remaining > 0 {
+                let skip = self.body_remaining.min(data.len());
+                self.body_remaining -= skip;
+                data = &data[skip..];
+                continue;
+            }
+
+            let take = (self.header.len() - self.header_len).min(data.len());
+            self.header[self.header_len..self.header_len + take].copy_from_slice(&data[..take]);
+            self.header_len += take;
+            data = &data[take..];
+
+            if self.header_len == self.header.len() {
+                let length = u16::from_be_bytes([self.header[3], self.header[4]]);
+                f(ContentType::from(self.header[0]), length);
+                self.header_len = 0;
+                self.body_remaining = usize::from(length);
+            }
+        }
+    }
+}
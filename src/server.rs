@@ -1,15 +1,27 @@
 use std::io;
+use std::mem;
+use std::num::NonZeroU64;
 #[cfg(unix)]
 use std::os::unix::io::{AsRawFd, RawFd};
 #[cfg(windows)]
 use std::os::windows::io::{AsRawSocket, RawSocket};
 use std::pin::Pin;
 use std::task::{Context, Poll};
+use std::time::Instant;
 
 use rustls::ServerConnection;
 use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
 
-use crate::common::{IoSession, Stream, TlsState};
+use crate::common::{self, next_connection_id, IoSession, Stream, TlsState};
+use crate::deadline::HandshakeDeadline;
+use crate::early_data_budget::EarlyDataPermit;
+use crate::key_update::KeyUpdate;
+use crate::rate_limit::ReadRateLimit;
+use crate::{
+    Batch, BatchWriter, CopyWithTee, DataAvailable, Downgrade, HandshakeInterrupted,
+    HandshakePhase, HandshakeProfile, HandshakeSummary, HttpVersion, KeepTrailingData,
+    KeyUpdateHandle, OnEstablished,
+};
 
 /// A wrapper around an underlying raw stream which implements the TLS or SSL
 /// protocol.
@@ -18,9 +30,34 @@ pub struct TlsStream<IO> {
     pub(crate) io: IO,
     pub(crate) session: ServerConnection,
     pub(crate) state: TlsState,
+    pub(crate) handshake_completed_at: Option<Instant>,
+    pub(crate) read_rate_limit: Option<Box<ReadRateLimit>>,
+    pub(crate) read_batch_limit: usize,
+    pub(crate) connection_id: u64,
+    pub(crate) key_update: KeyUpdate,
+    pub(crate) handshake_deadline: HandshakeDeadline,
+    pub(crate) on_established: OnEstablished,
+    pub(crate) early_data_permit: Option<EarlyDataPermit>,
 }
 
 impl<IO> TlsStream<IO> {
+    /// A stream with every setting at its default, which the acceptor then adjusts.
+    pub(crate) fn new(io: IO, session: ServerConnection) -> Self {
+        TlsStream {
+            io,
+            session,
+            state: TlsState::Stream,
+            handshake_completed_at: None,
+            read_rate_limit: None,
+            read_batch_limit: 1,
+            connection_id: next_connection_id(),
+            key_update: KeyUpdate::default(),
+            handshake_deadline: HandshakeDeadline::default(),
+            on_established: OnEstablished::default(),
+            early_data_permit: None,
+        }
+    }
+
     #[inline]
     pub fn get_ref(&self) -> (&IO, &ServerConnection) {
         (&self.io, &self.session)
@@ -35,6 +72,181 @@ impl<IO> TlsStream<IO> {
     pub fn into_inner(self) -> (IO, ServerConnection) {
         (self.io, self.session)
     }
+
+    /// See `client::TlsStream::buffered_bytes`.
+    #[inline]
+    pub fn buffered_bytes(&mut self) -> io::Result<(usize, usize)> {
+        common::buffered_bytes(&mut self.session)
+    }
+
+    /// See `client::TlsStream::try_into_inner`.
+    #[allow(clippy::result_large_err)] // the stream is handed back as is
+    pub fn try_into_inner(mut self) -> Result<(IO, ServerConnection), Self> {
+        match self.buffered_bytes() {
+            Ok((0, 0)) => Ok(self.into_inner()),
+            _ => Err(self),
+        }
+    }
+
+    /// See `client::TlsStream::connection_id`.
+    #[inline]
+    pub fn connection_id(&self) -> u64 {
+        self.connection_id
+    }
+
+    /// See `client::TlsStream::key_update_handle`.
+    #[inline]
+    pub fn key_update_handle(&mut self) -> KeyUpdateHandle {
+        self.key_update.handle()
+    }
+
+    /// See `client::TlsStream::handshake_summary`.
+    #[inline]
+    pub fn handshake_summary(&self) -> HandshakeSummary {
+        HandshakeSummary::new(&self.session)
+    }
+
+    /// See `client::TlsStream::auth_and_resumption`.
+    #[inline]
+    pub fn auth_and_resumption(&self) -> Option<HandshakeProfile> {
+        HandshakeProfile::new(&self.session)
+    }
+
+    /// See `client::TlsStream::key_exchange_group_name`.
+    #[inline]
+    pub fn key_exchange_group_name(&self) -> Option<&'static str> {
+        common::key_exchange_group_name(&self.session)
+    }
+
+    /// See `client::TlsStream::negotiated_http_version`.
+    #[inline]
+    pub fn negotiated_http_version(&self) -> Option<HttpVersion> {
+        common::negotiated_http_version(&self.session)
+    }
+
+    /// See `client::TlsStream::alpn_was_preferred`.
+    #[inline]
+    pub fn alpn_was_preferred(&self, preferred: &[u8]) -> bool {
+        self.session.alpn_protocol() == Some(preferred)
+    }
+
+    /// See `client::TlsStream::is_eof`.
+    #[inline]
+    pub fn is_eof(&self) -> bool {
+        !self.state.readable()
+    }
+
+    /// See `client::TlsStream::set_read_rate_limit`.
+    pub fn set_read_rate_limit(&mut self, limit: Option<NonZeroU64>) {
+        self.read_rate_limit = limit.map(|limit| Box::new(ReadRateLimit::new(limit)));
+    }
+
+    /// See `client::TlsStream::set_read_batch_limit`.
+    #[inline]
+    pub fn set_read_batch_limit(&mut self, limit: usize) {
+        self.read_batch_limit = limit;
+    }
+
+    /// See `client::TlsStream::handshake_completed_at`.
+    #[inline]
+    pub fn handshake_completed_at(&self) -> Option<Instant> {
+        self.handshake_completed_at
+    }
+
+    /// Runs `f` with a summary of the handshake at the moment it completes.
+    ///
+    /// Register it before the handshake through `Accept::on_established`. If the
+    /// handshake has already completed, `f` runs right away. Only the latest callback
+    /// is kept, and it runs at most once.
+    pub fn on_established(&mut self, f: impl FnOnce(HandshakeSummary) + Send + Sync + 'static) {
+        let completed = self.handshake_completed_at.is_some();
+        self.on_established
+            .set(Box::new(f), completed, &self.session);
+    }
+
+    /// See `client::TlsStream::wants_read`.
+    #[inline]
+    pub fn wants_read(&self) -> bool {
+        self.session.wants_read()
+    }
+
+    /// See `client::TlsStream::wants_write`.
+    #[inline]
+    pub fn wants_write(&self) -> bool {
+        self.session.wants_write()
+    }
+
+    /// See `client::TlsStream::memory_usage`.
+    pub fn memory_usage(&mut self) -> io::Result<usize> {
+        let (plaintext, tls) = self.buffered_bytes()?;
+        Ok(mem::size_of::<Self>() + plaintext + tls)
+    }
+}
+
+impl<IO> TlsStream<IO>
+where
+    IO: AsyncRead + AsyncWrite + Unpin,
+{
+    /// Drives any outstanding handshake to completion, without reading or writing
+    /// application data.
+    ///
+    /// `Accept` already completes the handshake before resolving, so this is only
+    /// needed by callers that want explicit control over when handshake traffic
+    /// happens. To interleave the initial handshake with other IO on the same
+    /// transport, poll `Accept` directly and use `Accept::get_mut`.
+    pub fn poll_handshake(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if self.session.is_handshaking() {
+            self.handshake_deadline.poll_check(cx)?;
+        }
+
+        let mut stream =
+            Stream::new(&mut self.io, &mut self.session).set_eof(!self.state.readable());
+
+        while stream.session.is_handshaking() {
+            ready!(stream.handshake(cx))
+                .map_err(|err| HandshakeInterrupted::wrap(err, handshake_phase(stream.session)))?;
+        }
+        self.handshake_completed_at.get_or_insert_with(Instant::now);
+        self.on_established.fire(stream.session);
+        self.early_data_permit = None;
+        self.handshake_deadline.clear();
+
+        stream.as_mut_pin().poll_flush(cx)
+    }
+
+    /// See `client::TlsStream::data_available`.
+    #[inline]
+    pub fn data_available(&mut self) -> DataAvailable<'_, Self> {
+        DataAvailable::new(self)
+    }
+
+    /// See `client::TlsStream::poll_data_available`.
+    pub fn poll_data_available(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let mut stream =
+            Stream::new(&mut self.io, &mut self.session).set_eof(!self.state.readable());
+        stream.poll_data_available(cx)
+    }
+
+    /// See `client::TlsStream::batch`.
+    pub fn batch<F, R>(&mut self, f: F) -> Batch<'_, Self, R>
+    where
+        F: FnOnce(&mut BatchWriter) -> io::Result<R>,
+    {
+        Batch::new(self, f)
+    }
+
+    /// See `client::TlsStream::copy_with_tee`.
+    pub fn copy_with_tee<'a, W, T>(
+        &'a mut self,
+        dst: &'a mut W,
+        tee: &'a mut T,
+    ) -> CopyWithTee<'a, Self, W, T>
+    where
+        W: AsyncWrite + Unpin,
+        T: AsyncWrite + Unpin,
+    {
+        CopyWithTee::new(self, dst, tee)
+    }
 }
 
 impl<IO> IoSession for TlsStream<IO> {
@@ -46,11 +258,29 @@ impl<IO> IoSession for TlsStream<IO> {
         false
     }
 
+    #[inline]
+    fn poll_deadline(&mut self, cx: &mut Context<'_>) -> io::Result<()> {
+        self.handshake_deadline.poll_check(cx)
+    }
+
+    #[inline]
+    fn handshake_phase(&self) -> HandshakePhase {
+        handshake_phase(&self.session)
+    }
+
     #[inline]
     fn get_mut(&mut self) -> (&mut TlsState, &mut Self::Io, &mut Self::Session) {
         (&mut self.state, &mut self.io, &mut self.session)
     }
 
+    #[inline]
+    fn handshake_completed(&mut self) {
+        self.handshake_completed_at.get_or_insert_with(Instant::now);
+        self.on_established.fire(&self.session);
+        self.early_data_permit = None;
+        self.handshake_deadline.clear();
+    }
+
     #[inline]
     fn into_io(self) -> Self::Io {
         self.io
@@ -67,16 +297,27 @@ where
         buf: &mut ReadBuf<'_>,
     ) -> Poll<io::Result<()>> {
         let this = self.get_mut();
-        let mut stream =
-            Stream::new(&mut this.io, &mut this.session).set_eof(!this.state.readable());
+        let mut stream = Stream::new(&mut this.io, &mut this.session)
+            .set_eof(!this.state.readable())
+            .set_read_batch(this.read_batch_limit);
 
         match &this.state {
             TlsState::Stream | TlsState::WriteShutdown => {
                 let prev = buf.remaining();
 
-                match stream.as_mut_pin().poll_read(cx, buf) {
+                let output = match &mut this.read_rate_limit {
+                    Some(limit) => {
+                        limit.poll_read(cx, buf, |cx, buf| stream.as_mut_pin().poll_read(cx, buf))
+                    }
+                    None => stream.as_mut_pin().poll_read(cx, buf),
+                };
+                match output {
                     Poll::Ready(Ok(())) => {
-                        if prev == buf.remaining() || stream.eof {
+                        // `Stream::poll_read` only completes without data once rustls
+                        // has seen close_notify or EOF; while no plaintext is available
+                        // yet, it stays pending. A read into an empty buffer says nothing
+                        // about EOF.
+                        if prev != 0 && prev == buf.remaining() {
                             this.state.shutdown_read();
                         }
 
@@ -108,6 +349,7 @@ where
         buf: &[u8],
     ) -> Poll<io::Result<usize>> {
         let this = self.get_mut();
+        this.key_update.apply(&mut this.session)?;
         let mut stream =
             Stream::new(&mut this.io, &mut this.session).set_eof(!this.state.readable());
         stream.as_mut_pin().poll_write(cx, buf)
@@ -121,6 +363,7 @@ where
         bufs: &[io::IoSlice<'_>],
     ) -> Poll<io::Result<usize>> {
         let this = self.get_mut();
+        this.key_update.apply(&mut this.session)?;
         let mut stream =
             Stream::new(&mut this.io, &mut this.session).set_eof(!this.state.readable());
         stream.as_mut_pin().poll_write_vectored(cx, bufs)
@@ -133,6 +376,7 @@ where
 
     fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
         let this = self.get_mut();
+        this.key_update.apply(&mut this.session)?;
         let mut stream =
             Stream::new(&mut this.io, &mut this.session).set_eof(!this.state.readable());
         stream.as_mut_pin().poll_flush(cx)
@@ -151,6 +395,43 @@ where
     }
 }
 
+fn handshake_phase(session: &ServerConnection) -> HandshakePhase {
+    match session.protocol_version() {
+        Some(_) => HandshakePhase::ServerHello,
+        None => HandshakePhase::Start,
+    }
+}
+
+impl<IO> TlsStream<KeepTrailingData<IO>> {
+    /// Returns the IO and the bytes the client sent after its `close_notify`, for
+    /// protocols that continue in plaintext once the TLS session ends.
+    ///
+    /// Fails, handing the stream back, unless the client's `close_notify` has been
+    /// received and all plaintext sent before it has been read.
+    #[allow(clippy::result_large_err)] // the stream is handed back as is
+    pub fn into_inner_after_close_notify(mut self) -> Result<(IO, Vec<u8>), Self> {
+        match self.session.process_new_packets() {
+            Ok(state) if state.peer_has_closed() && state.plaintext_bytes_to_read() == 0 => {
+                Ok(self.io.into_parts())
+            }
+            _ => Err(self),
+        }
+    }
+
+    /// Ends the TLS session but keeps the connection, for protocols that continue in
+    /// plaintext: sends `close_notify`, waits for the client's, and resolves to the IO
+    /// and any bytes the client sent after it.
+    ///
+    /// Plaintext that has not been read yet, or that the client sends before its
+    /// `close_notify`, makes the downgrade fail with `InvalidData`, since it would
+    /// otherwise be lost. On any failure the stream is handed back with the error, so
+    /// that such plaintext can still be read and the downgrade tried again.
+    #[inline]
+    pub fn downgrade(self) -> Downgrade<Self> {
+        Downgrade::new(self)
+    }
+}
+
 #[cfg(unix)]
 impl<IO> AsRawFd for TlsStream<IO>
 where
@@ -0,0 +1,125 @@
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// Room for a full TLS record of plaintext per read.
+const CHUNK_SIZE: usize = 16 * 1024;
+
+/// Future returned from `TlsStream::copy_with_tee`.
+pub struct CopyWithTee<'a, R, W, T> {
+    src: &'a mut R,
+    dst: &'a mut W,
+    tee: &'a mut T,
+    copied: u64,
+    buf: Box<[u8]>,
+    dst_pos: usize,
+    tee_pos: usize,
+    filled: usize,
+    need_flush: bool,
+    eof: bool,
+}
+
+impl<'a, R, W, T> CopyWithTee<'a, R, W, T> {
+    pub(crate) fn new(src: &'a mut R, dst: &'a mut W, tee: &'a mut T) -> Self {
+        CopyWithTee {
+            src,
+            dst,
+            tee,
+            copied: 0,
+            buf: vec![0; CHUNK_SIZE].into_boxed_slice(),
+            dst_pos: 0,
+            tee_pos: 0,
+            filled: 0,
+            need_flush: false,
+            eof: false,
+        }
+    }
+}
+
+impl<'a, R, W, T> Future for CopyWithTee<'a, R, W, T>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+    T: AsyncWrite + Unpin,
+{
+    type Output = io::Result<u64>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+
+        while !this.eof {
+            if this.dst_pos == this.filled && this.tee_pos == this.filled {
+                let mut buf = ReadBuf::new(&mut this.buf);
+                if Pin::new(&mut *this.src)
+                    .poll_read(cx, &mut buf)?
+                    .is_pending()
+                {
+                    // Nothing more to send for now, so push out what the writers have
+                    // buffered rather than leaving it until the next chunk arrives.
+                    if this.need_flush {
+                        let dst = Pin::new(&mut *this.dst).poll_flush(cx)?;
+                        let tee = Pin::new(&mut *this.tee).poll_flush(cx)?;
+                        this.need_flush = dst.is_pending() || tee.is_pending();
+                    }
+                    return Poll::Pending;
+                }
+
+                let n = buf.filled().len();
+                if n == 0 {
+                    this.eof = true;
+                    break;
+                }
+                this.dst_pos = 0;
+                this.tee_pos = 0;
+                this.filled = n;
+                this.copied += n as u64;
+                this.need_flush = true;
+            }
+
+            // Each writer is driven on its own, so the faster one is not held up by the
+            // slower before the chunk runs out; only then does reading wait for both.
+            let dst = poll_write_chunk(
+                &mut *this.dst,
+                cx,
+                &this.buf[..this.filled],
+                &mut this.dst_pos,
+            )?;
+            let tee = poll_write_chunk(
+                &mut *this.tee,
+                cx,
+                &this.buf[..this.filled],
+                &mut this.tee_pos,
+            )?;
+            if dst.is_pending() || tee.is_pending() {
+                return Poll::Pending;
+            }
+        }
+
+        let dst = Pin::new(&mut *this.dst).poll_flush(cx)?;
+        let tee = Pin::new(&mut *this.tee).poll_flush(cx)?;
+        match (dst, tee) {
+            (Poll::Ready(()), Poll::Ready(())) => Poll::Ready(Ok(this.copied)),
+            _ => Poll::Pending,
+        }
+    }
+}
+
+/// Writes `buf[*pos..]` to `dst` until it has all been written or `dst` is not ready.
+fn poll_write_chunk<W: AsyncWrite + Unpin>(
+    dst: &mut W,
+    cx: &mut Context<'_>,
+    buf: &[u8],
+    pos: &mut usize,
+) -> Poll<io::Result<()>> {
+    while *pos < buf.len() {
+        let n = ready!(Pin::new(&mut *dst).poll_write(cx, &buf[*pos..]))?;
+        if n == 0 {
+            return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+        }
+        *pos += n;
+    }
+    Poll::Ready(Ok(()))
+}
@@ -0,0 +1,265 @@
+use std::future::Future;
+use std::io;
+use std::ops::{Deref, DerefMut};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use rustls::{ConnectionCommon, SideData};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::common::{Stream, TlsState};
+use crate::{client, server};
+
+/// Enough for a full TLS record and the header of the next.
+const BUF_SIZE: usize = 16 * 1024 + 2048;
+
+/// Wraps the IO under a TLS stream so that data the peer sends after its
+/// `close_notify` can be recovered, for protocols that fall back to plaintext once
+/// the TLS session ends.
+///
+/// rustls discards anything it has read past a `close_notify`, so this wrapper never
+/// lets a read extend beyond the end of the current TLS record. Once the peer's
+/// `close_notify` has been read, `into_inner_after_close_notify` on the TLS stream
+/// returns the IO along with the bytes that followed it.
+///
+/// Reads from the IO are buffered; writes pass straight through.
+pub struct KeepTrailingData<IO> {
+    io: IO,
+    buf: Box<[u8]>,
+    pos: usize,
+    filled: usize,
+    header: [u8; 5],
+    header_len: usize,
+    body_remaining: usize,
+}
+
+impl<IO> KeepTrailingData<IO> {
+    #[inline]
+    pub fn new(io: IO) -> Self {
+        KeepTrailingData {
+            io,
+            buf: vec![0; BUF_SIZE].into_boxed_slice(),
+            pos: 0,
+            filled: 0,
+            header: [0; 5],
+            header_len: 0,
+            body_remaining: 0,
+        }
+    }
+
+    #[inline]
+    pub fn get_ref(&self) -> &IO {
+        &self.io
+    }
+
+    #[inline]
+    pub fn get_mut(&mut self) -> &mut IO {
+        &mut self.io
+    }
+
+    /// Returns the IO and the bytes read from it that have not been passed on yet.
+    #[inline]
+    pub fn into_parts(self) -> (IO, Vec<u8>) {
+        let buffered = self.buf[self.pos..self.filled].to_vec();
+        (self.io, buffered)
+    }
+
+    /// How many of the buffered bytes, up to `max`, may be passed on without crossing
+    /// the end of the current record.
+    fn available(&mut self, max: usize) -> usize {
+        let end = self.pos + max.min(self.filled - self.pos);
+        let mut data = &self.buf[self.pos..end];
+        let mut n = 0;
+
+        while !data.is_empty() {
+            if self.header_len < self.header.len() {
+                let take = (self.header.len() - self.header_len).min(data.len());
+                self.header[self.header_len..self.header_len + take].copy_from_slice(&data[..take]);
+                self.header_len += take;
+                if self.header_len == self.header.len() {
+                    self.body_remaining =
+                        usize::from(u16::from_be_bytes([self.header[3], self.header[4]]));
+                }
+                data = &data[take..];
+                n += take;
+            } else {
+                let take = self.body_remaining.min(data.len());
+                self.body_remaining -= take;
+                data = &data[take..];
+                n += take;
+            }
+
+            if self.header_len == self.header.len() && self.body_remaining == 0 {
+                self.header_len = 0;
+                break;
+            }
+        }
+
+        n
+    }
+}
+
+impl<IO: AsyncRead + Unpin> AsyncRead for KeepTrailingData<IO> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        if this.pos == this.filled {
+            let mut inner = ReadBuf::new(&mut this.buf);
+            ready!(Pin::new(&mut this.io).poll_read(cx, &mut inner))?;
+            this.pos = 0;
+            this.filled = inner.filled().len();
+        }
+
+        let n = this.available(buf.remaining());
+        buf.put_slice(&this.buf[this.pos..this.pos + n]);
+        this.pos += n;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<IO: AsyncWrite + Unpin> AsyncWrite for KeepTrailingData<IO> {
+    #[inline]
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().io).poll_write(cx, buf)
+    }
+
+    #[inline]
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().io).poll_write_vectored(cx, bufs)
+    }
+
+    #[inline]
+    fn is_write_vectored(&self) -> bool {
+        self.io.is_write_vectored()
+    }
+
+    #[inline]
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().io).poll_flush(cx)
+    }
+
+    #[inline]
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().io).poll_shutdown(cx)
+    }
+}
+
+/// Future returned from `TlsStream::downgrade`.
+pub struct Downgrade<S> {
+    stream: Option<S>,
+}
+
+impl<S> Downgrade<S> {
+    #[inline]
+    pub(crate) fn new(stream: S) -> Self {
+        Downgrade {
+            stream: Some(stream),
+        }
+    }
+}
+
+impl<IO: AsyncRead + AsyncWrite + Unpin> Future
+    for Downgrade<client::TlsStream<KeepTrailingData<IO>>>
+{
+    type Output = Result<(IO, Vec<u8>), (io::Error, client::TlsStream<KeepTrailingData<IO>>)>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let stream = this.stream.as_mut().expect("polled after completion");
+        let result = ready!(poll_downgrade(
+            &mut stream.state,
+            &mut stream.io,
+            &mut stream.session,
+            cx
+        ));
+        let stream = this.stream.take().unwrap();
+        Poll::Ready(match result {
+            Ok(()) => Ok(stream.io.into_parts()),
+            Err(err) => Err((err, stream)),
+        })
+    }
+}
+
+impl<IO: AsyncRead + AsyncWrite + Unpin> Future
+    for Downgrade<server::TlsStream<KeepTrailingData<IO>>>
+{
+    type Output = Result<(IO, Vec<u8>), (io::Error, server::TlsStream<KeepTrailingData<IO>>)>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let stream = this.stream.as_mut().expect("polled after completion");
+        let result = ready!(poll_downgrade(
+            &mut stream.state,
+            &mut stream.io,
+            &mut stream.session,
+            cx
+        ));
+        let stream = this.stream.take().unwrap();
+        Poll::Ready(match result {
+            Ok(()) => Ok(stream.io.into_parts()),
+            Err(err) => Err((err, stream)),
+        })
+    }
+}
+
+/// Sends close_notify, then reads up to the peer's, leaving whatever follows it in
+/// `io`.
+fn poll_downgrade<IO, C, SD>(
+    state: &mut TlsState,
+    io: &mut KeepTrailingData<IO>,
+    session: &mut C,
+    cx: &mut Context<'_>,
+) -> Poll<io::Result<()>>
+where
+    IO: AsyncRead + AsyncWrite + Unpin,
+    C: DerefMut + Deref<Target = ConnectionCommon<SD>>,
+    SD: SideData,
+{
+    if session.is_handshaking() {
+        return Poll::Ready(Err(io::Error::new(
+            io::ErrorKind::NotConnected,
+            "cannot downgrade before the handshake has completed",
+        )));
+    }
+
+    if state.writeable() {
+        session.send_close_notify();
+        state.shutdown_write();
+    }
+
+    let mut stream = Stream::new(io, session);
+    ready!(Pin::new(&mut stream).poll_flush(cx))?;
+
+    loop {
+        let io_state = stream
+            .session
+            .process_new_packets()
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        if io_state.plaintext_bytes_to_read() > 0 {
+            return Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "peer sent application data while downgrading",
+            )));
+        }
+        if io_state.peer_has_closed() {
+            state.shutdown_read();
+            return Poll::Ready(Ok(()));
+        }
+
+        if ready!(stream.read_io(cx))? == 0 {
+            return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+        }
+    }
+}
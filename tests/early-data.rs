@@ -2,36 +2,18 @@
 
 use std::io::{self, Read, Write};
 use std::net::{SocketAddr, TcpListener};
-use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-use std::task::{Context, Poll};
 use std::thread;
 
-use futures_util::{future::Future, ready};
+use futures_util::future::poll_fn;
+use rustls::server::Acceptor;
 use rustls::{self, ClientConfig, ServerConnection, Stream};
-use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt, ReadBuf};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::TcpStream;
-use tokio_rustls::{client::TlsStream, TlsConnector};
-
-struct Read1<T>(T);
-
-impl<T: AsyncRead + Unpin> Future for Read1<T> {
-    type Output = io::Result<()>;
-
-    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-        let mut buf = [0];
-        let mut buf = ReadBuf::new(&mut buf);
-
-        ready!(Pin::new(&mut self.0).poll_read(cx, &mut buf))?;
-
-        if buf.filled().is_empty() {
-            Poll::Ready(Ok(()))
-        } else {
-            cx.waker().wake_by_ref();
-            Poll::Pending
-        }
-    }
-}
+use tokio_rustls::{
+    client::TlsStream, EarlyDataBudget, LazyConfigAcceptor, TlsAcceptor, TlsConnector,
+};
 
 async fn send(
     config: Arc<ClientConfig>,
@@ -65,6 +47,168 @@ async fn test_0rtt_vectored() -> io::Result<()> {
 }
 
 async fn test_0rtt_impl(vectored: bool) -> io::Result<()> {
+    let (client, addr) = start_server()?;
+
+    let (io, buf) = send(client.clone(), addr, b"hello", vectored).await?;
+    assert!(!io.get_ref().1.is_early_data_accepted());
+    assert_eq!("LATE:hello", String::from_utf8_lossy(&buf));
+
+    let (io, buf) = send(client, addr, b"world!", vectored).await?;
+    assert!(io.get_ref().1.is_early_data_accepted());
+    assert_eq!("EARLY:world!LATE:", String::from_utf8_lossy(&buf));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_0rtt_poll_handshake() -> io::Result<()> {
+    async fn send_then_handshake(
+        config: Arc<ClientConfig>,
+        addr: SocketAddr,
+        data: &[u8],
+    ) -> io::Result<(TlsStream<TcpStream>, Vec<u8>)> {
+        let connector = TlsConnector::from(config).early_data(true);
+        let stream = TcpStream::connect(&addr).await?;
+        let domain = pki_types::ServerName::try_from("foobar.com").unwrap();
+
+        let mut stream = connector.connect(domain, stream).await?;
+        stream.write_all(data).await?;
+
+        // Ends the early data phase, resending the data if the server rejected it.
+        poll_fn(|cx| stream.poll_handshake(cx)).await?;
+        assert!(!stream.get_ref().1.is_handshaking());
+        stream.shutdown().await?;
+
+        let mut buf = Vec::new();
+        stream.read_to_end(&mut buf).await?;
+        Ok((stream, buf))
+    }
+
+    let (client, addr) = start_server()?;
+
+    let (io, buf) = send_then_handshake(client.clone(), addr, b"hello").await?;
+    assert!(!io.get_ref().1.is_early_data_accepted());
+    assert_eq!("LATE:hello", String::from_utf8_lossy(&buf));
+
+    let (io, buf) = send_then_handshake(client, addr, b"world!").await?;
+    assert!(io.get_ref().1.is_early_data_accepted());
+    assert_eq!("EARLY:world!LATE:", String::from_utf8_lossy(&buf));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_0rtt_flush_keeps_handshaking() -> io::Result<()> {
+    let (client, addr) = start_server()?;
+
+    // A full handshake first, for a ticket to resume with.
+    send(client.clone(), addr, b"hello", false).await?;
+
+    let connector = TlsConnector::from(client)
+        .early_data(true)
+        .flush_completes_handshake(false);
+    let stream = TcpStream::connect(&addr).await?;
+    let domain = pki_types::ServerName::try_from("foobar.com").unwrap();
+
+    let established = Arc::new(AtomicBool::new(false));
+    let flag = established.clone();
+    let mut stream = connector
+        .connect(domain, stream)
+        .on_established(move |_| flag.store(true, Ordering::SeqCst))
+        .await?;
+    assert!(stream.is_early_data_phase());
+    stream.write_all(b"world!").await?;
+    stream.flush().await?;
+    assert!(stream.is_early_data_phase());
+    assert!(!established.load(Ordering::SeqCst));
+    assert!(stream.get_ref().1.is_handshaking());
+    assert!(stream.handshake_completed_at().is_none());
+
+    // Reading completes the handshake.
+    let mut buf = [0; 6];
+    stream.read_exact(&mut buf).await?;
+    assert!(!stream.get_ref().1.is_handshaking());
+    assert!(!stream.is_early_data_phase());
+    assert!(stream.handshake_completed_at().is_some());
+    assert!(established.load(Ordering::SeqCst));
+    assert!(stream.get_ref().1.is_early_data_accepted());
+    assert_eq!(&buf, b"EARLY:");
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_0rtt_budget() -> io::Result<()> {
+    async fn send(
+        connector: &TlsConnector,
+        acceptor: &TlsAcceptor,
+        lazy: bool,
+    ) -> io::Result<bool> {
+        let (cstream, sstream) = tokio::io::duplex(8192);
+        let domain = pki_types::ServerName::try_from("foobar.com").unwrap();
+
+        let (accepted, received) = tokio::try_join!(
+            async {
+                let mut stream = connector.connect(domain, cstream).await?;
+                stream.write_all(b"hello").await?;
+                stream.flush().await?;
+                // Reading after the handshake picks up the server's tickets.
+                let mut buf = [0; 2];
+                stream.read_exact(&mut buf).await?;
+                stream.shutdown().await?;
+                Ok::<_, io::Error>(stream.get_ref().1.is_early_data_accepted())
+            },
+            async {
+                let mut stream = if lazy {
+                    LazyConfigAcceptor::new(Acceptor::default(), sstream)
+                        .await?
+                        .into_stream_with_acceptor(acceptor, |_| ())
+                        .await?
+                } else {
+                    acceptor.accept(sstream).await?
+                };
+                stream.write_all(b"ok").await?;
+                stream.flush().await?;
+                let mut buf = Vec::new();
+                stream.read_to_end(&mut buf).await?;
+                Ok(buf)
+            }
+        )?;
+        // The server never reads early data, so only data sent after the handshake
+        // arrives.
+        assert_eq!(received.is_empty(), accepted);
+        Ok(accepted)
+    }
+
+    let (mut sconfig, mut cconfig) = utils::make_configs();
+    sconfig.max_early_data_size = 8192;
+    cconfig.enable_early_data = true;
+    let budget = EarlyDataBudget::new(1);
+    let acceptor = TlsAcceptor::from(Arc::new(sconfig)).early_data_budget(budget.clone());
+    let connector = TlsConnector::from(Arc::new(cconfig)).early_data(true);
+
+    // A full handshake first, for a ticket to resume with.
+    assert!(!send(&connector, &acceptor, false).await?);
+    assert!(send(&connector, &acceptor, false).await?);
+    assert_eq!(budget.in_use(), 0);
+
+    // While another handshake holds the only slot, 0-RTT is rejected, whichever way
+    // the connection is accepted. The lazy path goes first, as the tickets it issues do
+    // not allow early data.
+    let pending = acceptor.accept(tokio::io::duplex(1).1);
+    assert_eq!(budget.in_use(), 1);
+    assert!(!send(&connector, &acceptor, true).await?);
+    assert!(!send(&connector, &acceptor, false).await?);
+
+    drop(pending);
+    assert_eq!(budget.in_use(), 0);
+    assert!(send(&connector, &acceptor, false).await?);
+    assert!(send(&connector, &acceptor, true).await?);
+    assert_eq!(budget.in_use(), 0);
+    Ok(())
+}
+
+/// Starts an echo server accepting 0-RTT data, returning a matching client config.
+fn start_server() -> io::Result<(Arc<ClientConfig>, SocketAddr)> {
     let (mut server, mut client) = utils::make_configs();
     server.max_early_data_size = 8192;
     let server = Arc::new(server);
@@ -106,15 +250,7 @@ async fn test_0rtt_impl(vectored: bool) -> io::Result<()> {
     let client = Arc::new(client);
     let addr = SocketAddr::from(([127, 0, 0, 1], server_port));
 
-    let (io, buf) = send(client.clone(), addr, b"hello", vectored).await?;
-    assert!(!io.get_ref().1.is_early_data_accepted());
-    assert_eq!("LATE:hello", String::from_utf8_lossy(&buf));
-
-    let (io, buf) = send(client, addr, b"world!", vectored).await?;
-    assert!(io.get_ref().1.is_early_data_accepted());
-    assert_eq!("EARLY:world!LATE:", String::from_utf8_lossy(&buf));
-
-    Ok(())
+    Ok((client, addr))
 }
 
 // Include `utils` module
@@ -1,18 +1,26 @@
 use std::io::{Cursor, ErrorKind};
 use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc::channel;
 use std::sync::Arc;
-use std::time::Duration;
+use std::task::{Context, Poll};
+use std::time::{Duration, SystemTime};
 use std::{io, thread};
 
 use futures_util::future::TryFutureExt;
 use lazy_static::lazy_static;
 use rustls::ClientConfig;
-use tokio::io::{copy, split, AsyncReadExt, AsyncWriteExt};
+use tokio::io::{
+    copy, duplex, split, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream,
+};
 use tokio::net::{TcpListener, TcpStream};
 use tokio::sync::oneshot;
 use tokio::{runtime, time};
-use tokio_rustls::{LazyConfigAcceptor, TlsAcceptor, TlsConnector};
+use tokio_rustls::{
+    client, server, Direction, HandshakeInterrupted, HandshakePhase, HandshakeProfile, HttpVersion,
+    Instrumented, IoStats, KeepTrailingData, LazyConfigAcceptor, ObserveRecords, RecordMeta,
+    TlsAcceptor, TlsConnector,
+};
 
 lazy_static! {
     static ref TEST_SERVER: SocketAddr = {
@@ -79,6 +87,39 @@ async fn start_client(addr: SocketAddr, domain: &str, config: Arc<ClientConfig>)
     Ok(())
 }
 
+/// Completes a handshake with the default configs over an in-memory pipe buffering
+/// up to `max_buf_size` bytes in each direction.
+async fn connected_pair(
+    max_buf_size: usize,
+) -> io::Result<(
+    client::TlsStream<DuplexStream>,
+    server::TlsStream<DuplexStream>,
+)> {
+    let (sconfig, cconfig) = utils::make_configs();
+    let connector = TlsConnector::from(Arc::new(cconfig));
+    let acceptor = TlsAcceptor::from(Arc::new(sconfig));
+    let (cstream, sstream) = duplex(max_buf_size);
+    handshake(&connector, &acceptor, cstream, sstream).await
+}
+
+/// Completes a handshake between `connector` and `acceptor`, connected through
+/// `cstream` and `sstream`.
+async fn handshake<C, S>(
+    connector: &TlsConnector,
+    acceptor: &TlsAcceptor,
+    cstream: C,
+    sstream: S,
+) -> io::Result<(client::TlsStream<C>, server::TlsStream<S>)>
+where
+    C: AsyncRead + AsyncWrite + Unpin,
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let domain = pki_types::ServerName::try_from(utils::TEST_SERVER_DOMAIN)
+        .unwrap()
+        .to_owned();
+    tokio::try_join!(connector.connect(domain, cstream), acceptor.accept(sstream))
+}
+
 #[tokio::test]
 async fn pass() -> io::Result<()> {
     // TODO: not sure how to resolve this right now but since
@@ -145,6 +186,39 @@ async fn test_lazy_config_acceptor() -> io::Result<()> {
     Ok(())
 }
 
+#[tokio::test]
+async fn lazy_config_acceptor_raw_client_hello() -> io::Result<()> {
+    let (sconfig, cconfig) = utils::make_configs();
+
+    let (cstream, sstream) = tokio::io::duplex(1200);
+    let domain = pki_types::ServerName::try_from("foobar.com")
+        .unwrap()
+        .to_owned();
+    tokio::spawn(async move {
+        let connector = crate::TlsConnector::from(Arc::new(cconfig));
+        let mut client = connector.connect(domain, cstream).await.unwrap();
+        let mut buf = Vec::new();
+        client.read_to_end(&mut buf).await.unwrap();
+    });
+
+    let acceptor = LazyConfigAcceptor::new(rustls::server::Acceptor::default(), sstream)
+        .record_client_hello(true);
+    let start = acceptor.await?;
+
+    let raw = start.raw_client_hello().unwrap();
+    // A ClientHello handshake header, with a length matching the message.
+    assert_eq!(raw[0], 1);
+    assert_eq!(
+        u32::from_be_bytes([0, raw[1], raw[2], raw[3]]) as usize,
+        raw.len() - 4
+    );
+    assert!(raw.windows(10).any(|w| w == b"foobar.com"));
+
+    let mut stream = start.into_stream(Arc::new(sconfig)).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
 // This test is a follow-up from https://github.com/tokio-rs/tls/issues/85
 #[tokio::test]
 async fn lazy_config_acceptor_eof() {
@@ -163,6 +237,21 @@ async fn lazy_config_acceptor_eof() {
     }
 }
 
+#[tokio::test]
+async fn lazy_config_acceptor_idle() {
+    let (mut cstream, sstream) = tokio::io::duplex(1200);
+    let mut acceptor = LazyConfigAcceptor::new(rustls::server::Acceptor::default(), sstream);
+
+    let timeout = Duration::from_millis(10);
+    assert!(time::timeout(timeout, &mut acceptor).await.is_err());
+    assert!(!acceptor.has_received_data());
+
+    // The start of a handshake record, but not a complete ClientHello
+    cstream.write_all(b"\x16\x03\x01\x00\xa5").await.unwrap();
+    assert!(time::timeout(timeout, &mut acceptor).await.is_err());
+    assert!(acceptor.has_received_data());
+}
+
 #[tokio::test]
 async fn lazy_config_acceptor_take_io() -> Result<(), rustls::Error> {
     let (mut cstream, sstream) = tokio::io::duplex(1200);
@@ -202,6 +291,1233 @@ async fn lazy_config_acceptor_take_io() -> Result<(), rustls::Error> {
     Ok(())
 }
 
+#[tokio::test]
+async fn lazy_config_acceptor_send() -> io::Result<()> {
+    fn assert_send<T: Send>(_: &T) {}
+
+    let (sconfig, cconfig) = utils::make_configs();
+
+    // Intentionally small so that the ClientHello is only partially read by the first poll
+    let (cstream, sstream) = tokio::io::duplex(64);
+    let domain = pki_types::ServerName::try_from("foobar.com")
+        .unwrap()
+        .to_owned();
+    tokio::spawn(async move {
+        let connector = crate::TlsConnector::from(Arc::new(cconfig));
+        let mut client = connector.connect(domain, cstream).await.unwrap();
+        client.write_all(b"hello, world!").await.unwrap();
+        client.shutdown().await.unwrap();
+
+        let mut buf = Vec::new();
+        client.read_to_end(&mut buf).await.unwrap();
+    });
+
+    // Let the client start writing its ClientHello
+    tokio::task::yield_now().await;
+
+    let mut acceptor = LazyConfigAcceptor::new(rustls::server::Acceptor::default(), sstream);
+    assert!(futures_util::poll!(&mut acceptor).is_pending());
+    // Part of the ClientHello is now buffered inside the acceptor, and moves with it.
+    assert!(acceptor.has_received_data());
+    assert_send(&acceptor);
+
+    // Finish the handshake on another task, with the bytes already buffered in the acceptor
+    let handle = tokio::spawn(async move {
+        let start = acceptor.await?;
+        assert_eq!(start.client_hello().server_name(), Some("foobar.com"));
+        let mut stream = start.into_stream(Arc::new(sconfig)).await?;
+
+        let mut buf = Vec::new();
+        stream.read_to_end(&mut buf).await?;
+        stream.shutdown().await?;
+        Ok(buf) as io::Result<Vec<u8>>
+    });
+
+    assert_eq!(handle.await.unwrap()?, b"hello, world!");
+    Ok(())
+}
+
+#[tokio::test]
+async fn negotiated_http_version() -> io::Result<()> {
+    let (mut sconfig, mut cconfig) = utils::make_configs();
+    sconfig.alpn_protocols = vec![b"h2".to_vec()];
+    cconfig.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+    let connector = TlsConnector::from(Arc::new(cconfig));
+    let acceptor = TlsAcceptor::from(Arc::new(sconfig));
+
+    let (cstream, sstream) = tokio::io::duplex(1200);
+    let (client, server) = handshake(&connector, &acceptor, cstream, sstream).await?;
+
+    assert_eq!(client.negotiated_http_version(), Some(HttpVersion::Http2));
+    assert_eq!(server.negotiated_http_version(), Some(HttpVersion::Http2));
+    assert_eq!(
+        tokio_rustls::TlsStream::from(client).negotiated_http_version(),
+        Some(HttpVersion::Http2)
+    );
+    assert_eq!(HttpVersion::from_alpn(b"smtp"), None);
+    assert!(server.alpn_was_preferred(b"h2"));
+    assert!(!server.alpn_was_preferred(b"http/1.1"));
+    Ok(())
+}
+
+#[tokio::test]
+async fn connect_expecting_alpn() -> io::Result<()> {
+    let (mut sconfig, mut cconfig) = utils::make_configs();
+    sconfig.alpn_protocols = vec![b"h2".to_vec()];
+    // Session tickets would be written after the client has gone away.
+    sconfig.send_tls13_tickets = 0;
+    cconfig.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+    let domain = pki_types::ServerName::try_from("foobar.com")
+        .unwrap()
+        .to_owned();
+    let connector = TlsConnector::from(Arc::new(cconfig));
+    let acceptor = TlsAcceptor::from(Arc::new(sconfig));
+
+    let (cstream, sstream) = tokio::io::duplex(1200);
+    let (client, _server) = tokio::try_join!(
+        connector.connect_expecting_alpn(domain.clone(), cstream, &[b"h2"]),
+        acceptor.accept(sstream)
+    )?;
+    assert_eq!(client.get_ref().1.alpn_protocol(), Some(&b"h2"[..]));
+
+    let (cstream, sstream) = tokio::io::duplex(1200);
+    let (client, server) = tokio::join!(
+        connector.connect_expecting_alpn(domain, cstream, &[b"http/1.1"]),
+        async {
+            let mut server = acceptor.accept(sstream).await?;
+            let mut buf = Vec::new();
+            server.read_to_end(&mut buf).await?;
+            io::Result::Ok(buf)
+        }
+    );
+
+    let err = client.unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidData);
+    assert_eq!(
+        err.get_ref()
+            .and_then(|err| err.downcast_ref::<rustls::Error>()),
+        Some(&rustls::Error::NoApplicationProtocol)
+    );
+    // The server sees a clean close.
+    assert!(server?.is_empty());
+    Ok(())
+}
+
+#[tokio::test]
+async fn auth_and_resumption() -> io::Result<()> {
+    let (sconfig, cconfig) = utils::make_configs();
+    let connector = TlsConnector::from(Arc::new(cconfig));
+    let acceptor = TlsAcceptor::from(Arc::new(sconfig));
+
+    let mut profiles = Vec::new();
+    for _ in 0..2 {
+        let (cstream, sstream) = tokio::io::duplex(4096);
+        let (mut client, mut server) = handshake(&connector, &acceptor, cstream, sstream).await?;
+
+        // Reading takes in the session tickets sent after the handshake.
+        server.write_all(b"x").await?;
+        server.flush().await?;
+        let mut buf = [0; 1];
+        client.read_exact(&mut buf).await?;
+
+        assert_eq!(client.auth_and_resumption(), server.auth_and_resumption());
+        assert!(!client.is_early_data_phase());
+        profiles.push(client.auth_and_resumption());
+    }
+
+    assert_eq!(
+        profiles,
+        [
+            Some(HandshakeProfile::FullCertificate),
+            Some(HandshakeProfile::ResumedTicket)
+        ]
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn handshake_summary() -> io::Result<()> {
+    let (mut sconfig, mut cconfig) = utils::make_configs();
+    sconfig.alpn_protocols = vec![b"h2".to_vec()];
+    cconfig.alpn_protocols = vec![b"h2".to_vec()];
+
+    let connector = TlsConnector::from(Arc::new(cconfig));
+    let acceptor = TlsAcceptor::from(Arc::new(sconfig));
+
+    let (cstream, sstream) = tokio::io::duplex(1200);
+    let (client, server) = handshake(&connector, &acceptor, cstream, sstream).await?;
+
+    let summary = client.handshake_summary();
+    assert_eq!(summary.protocol_version, Some("TLSv1_3"));
+    assert!(summary.cipher_suite.is_some());
+    assert!(summary.key_exchange_group.is_some());
+    assert_eq!(client.key_exchange_group_name(), summary.key_exchange_group);
+    assert_eq!(summary.alpn_protocol.as_deref(), Some("h2"));
+    assert_eq!(summary.handshake_kind, Some("full"));
+    assert_eq!(summary.peer_certificates, 2);
+    // SEQUENCE { SET { SEQUENCE { commonName, UTF8String } } }
+    let mut subject = b"\x30\x21\x31\x1f\x30\x1d\x06\x03\x55\x04\x03\x0c\x16".to_vec();
+    subject.extend_from_slice(b"rcgen self signed cert");
+    assert_eq!(summary.peer_subject, Some(subject));
+
+    let summary = server.handshake_summary();
+    assert_eq!(summary.protocol_version, Some("TLSv1_3"));
+    assert_eq!(summary.peer_certificates, 0);
+    assert_eq!(summary.peer_subject, None);
+    Ok(())
+}
+
+#[tokio::test]
+async fn poll_handshake_after_connect() -> io::Result<()> {
+    let (client, server) = connected_pair(1200).await?;
+    let mut client = tokio_rustls::TlsStream::from(client);
+    let mut server = tokio_rustls::TlsStream::from(server);
+
+    // The handshake is already done, so there is nothing left to drive.
+    let mut cx = Context::from_waker(futures_util::task::noop_waker_ref());
+    assert!(matches!(
+        client.poll_handshake(&mut cx),
+        Poll::Ready(Ok(()))
+    ));
+    assert!(matches!(
+        server.poll_handshake(&mut cx),
+        Poll::Ready(Ok(()))
+    ));
+    assert!(!client.get_ref().1.is_handshaking());
+    assert!(client.wants_read());
+    assert!(!client.wants_write());
+    assert!(client.handshake_completed_at().is_some());
+    assert!(server.handshake_completed_at().is_some());
+
+    client.write_all(b"hello").await?;
+    client.flush().await?;
+    let mut buf = [0; 5];
+    server.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"hello");
+    Ok(())
+}
+
+#[tokio::test]
+async fn connect_lazy() -> io::Result<()> {
+    let (sconfig, cconfig) = utils::make_configs();
+
+    let (cstream, sstream) = tokio::io::duplex(1200);
+    let domain = pki_types::ServerName::try_from("foobar.com")
+        .unwrap()
+        .to_owned();
+    let connector = TlsConnector::from(Arc::new(cconfig));
+    let acceptor = TlsAcceptor::from(Arc::new(sconfig));
+
+    let mut client = connector.connect_lazy(domain, cstream)?;
+    assert!(client.get_ref().1.is_handshaking());
+    assert!(client.handshake_completed_at().is_none());
+
+    let server = async move {
+        let mut server = acceptor.accept(sstream).await?;
+        server.write_all(b"hello").await?;
+        server.flush().await?;
+        io::Result::Ok(server)
+    };
+
+    // Reading first must still drive the client's side of the handshake.
+    let client = async move {
+        let mut buf = [0; 5];
+        client.read_exact(&mut buf).await?;
+        assert_eq!(&buf, b"hello");
+        io::Result::Ok(client)
+    };
+
+    let (client, server) = tokio::try_join!(client, server)?;
+    assert!(!client.get_ref().1.is_handshaking());
+    assert!(client.handshake_completed_at().is_some());
+    assert!(server.handshake_completed_at().unwrap() <= std::time::Instant::now());
+    Ok(())
+}
+
+#[tokio::test]
+async fn connect_lazy_error() -> io::Result<()> {
+    let (sconfig, cconfig) = utils::make_configs();
+
+    let (cstream, sstream) = tokio::io::duplex(1200);
+    let domain = pki_types::ServerName::try_from("wrong.example")
+        .unwrap()
+        .to_owned();
+    let connector = TlsConnector::from(Arc::new(cconfig));
+    let acceptor = TlsAcceptor::from(Arc::new(sconfig));
+
+    let mut client = connector.connect_lazy(domain, cstream)?;
+    let server = tokio::spawn(async move { acceptor.accept(sstream).await.map(|_| ()) });
+
+    let err = async {
+        client.write_all(b"hello").await?;
+        client.flush().await
+    }
+    .await
+    .unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidData);
+    assert!(server.await.unwrap().is_err());
+    Ok(())
+}
+
+#[tokio::test]
+async fn is_eof_after_close_notify() -> io::Result<()> {
+    let (mut client, mut server) = connected_pair(1200).await?;
+
+    client.write_all(b"hello").await?;
+    client.shutdown().await?;
+
+    let mut buf = [0; 5];
+    server.read_exact(&mut buf).await?;
+    assert!(!server.is_eof());
+    assert_eq!(server.read(&mut buf).await?, 0);
+    assert!(server.is_eof());
+    assert!(!client.is_eof());
+    Ok(())
+}
+
+#[tokio::test]
+async fn sporadic_data_is_not_eof() -> io::Result<()> {
+    let (mut client, mut server) = connected_pair(1200).await?;
+
+    let mut buf = [0; 8];
+    for data in [&b"one"[..], b"two", b"three"] {
+        // Nothing to read yet: the read stays pending rather than reporting EOF.
+        let idle = time::timeout(Duration::from_millis(20), client.read(&mut buf)).await;
+        assert!(idle.is_err());
+        assert!(!client.is_eof());
+
+        server.write_all(data).await?;
+        server.flush().await?;
+        let n = client.read(&mut buf).await?;
+        assert_eq!(&buf[..n], data);
+        assert!(!client.is_eof());
+    }
+
+    server.shutdown().await?;
+    assert_eq!(client.read(&mut buf).await?, 0);
+    assert!(client.is_eof());
+    Ok(())
+}
+
+#[tokio::test]
+async fn memory_usage() -> io::Result<()> {
+    let (mut client, mut server) = connected_pair(4096).await?;
+    let idle = client.memory_usage()?;
+
+    server.write_all(&[0; 1000]).await?;
+    server.flush().await?;
+    let mut buf = [0; 1];
+    client.read_exact(&mut buf).await?;
+    assert_eq!(client.memory_usage()?, idle + 999);
+
+    let mut buf = [0; 999];
+    client.read_exact(&mut buf).await?;
+    assert_eq!(client.memory_usage()?, idle);
+    Ok(())
+}
+
+#[tokio::test]
+async fn memory_usage_corrupt_record() -> io::Result<()> {
+    let (mut client, _server) = connected_pair(4096).await?;
+
+    let mut record: &[u8] = &[&[0x17, 0x03, 0x03, 0x00, 0x20][..], &[0; 0x20]].concat();
+    client.get_mut().1.read_tls(&mut record)?;
+    let err = client.memory_usage().unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    Ok(())
+}
+
+#[tokio::test]
+async fn buffered_bytes_corrupt_record() -> io::Result<()> {
+    let (mut client, _server) = connected_pair(4096).await?;
+
+    let mut record: &[u8] = &[&[0x17, 0x03, 0x03, 0x00, 0x20][..], &[0; 0x20]].concat();
+    client.get_mut().1.read_tls(&mut record)?;
+    let err = client.buffered_bytes().unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    assert!(client.try_into_inner().is_err());
+    Ok(())
+}
+
+#[tokio::test]
+async fn buffered_bytes() -> io::Result<()> {
+    let (mut client, mut server) = connected_pair(4096).await?;
+    assert_eq!(client.buffered_bytes()?, (0, 0));
+
+    server.write_all(&[0; 1000]).await?;
+    server.flush().await?;
+    let mut buf = [0; 1];
+    client.read_exact(&mut buf).await?;
+    assert_eq!(client.buffered_bytes()?, (999, 0));
+    let mut client = client.try_into_inner().unwrap_err();
+
+    let mut buf = [0; 999];
+    client.read_exact(&mut buf).await?;
+    io::Write::write_all(&mut client.get_mut().1.writer(), b"unsent")?;
+    let (plaintext, ciphertext) = client.buffered_bytes()?;
+    assert_eq!(plaintext, 0);
+    assert!(ciphertext > 0);
+    let mut client = client.try_into_inner().unwrap_err();
+
+    client.flush().await?;
+    assert!(client.try_into_inner().is_ok());
+    Ok(())
+}
+
+#[tokio::test]
+async fn reading_a_flood_yields() -> io::Result<()> {
+    const LEN: usize = 4 << 20;
+    let (mut client, mut server) = connected_pair(2 * LEN).await?;
+    server.write_all(&vec![0; LEN]).await?;
+    server.flush().await?;
+
+    // With all of it buffered in the duplex, no read waits for the peer; only the
+    // runtime's budget, which the IO consumes, gives the other task a turn.
+    let ran = Arc::new(AtomicBool::new(false));
+    let flag = ran.clone();
+    tokio::spawn(async move { flag.store(true, Ordering::SeqCst) });
+
+    let mut buf = vec![0; 64 * 1024];
+    let mut received = 0;
+    let mut yielded = false;
+    while received < LEN {
+        received += client.read(&mut buf).await?;
+        yielded |= received < LEN && ran.load(Ordering::SeqCst);
+    }
+    assert!(yielded);
+    Ok(())
+}
+
+#[tokio::test]
+async fn max_handshake_duration() -> io::Result<()> {
+    let (sconfig, cconfig) = utils::make_configs();
+    let limit = Duration::from_millis(50);
+    let connector = TlsConnector::from(Arc::new(cconfig)).max_handshake_duration(limit);
+    let acceptor = TlsAcceptor::from(Arc::new(sconfig)).max_handshake_duration(limit);
+    let domain = pki_types::ServerName::try_from("foobar.com")
+        .unwrap()
+        .to_owned();
+
+    // A client that trickles its ClientHello one byte at a time keeps making progress,
+    // but not enough.
+    let (mut cstream, sstream) = tokio::io::duplex(1200);
+    let trickle = tokio::spawn(async move {
+        let mut hello = Vec::new();
+        let mut session =
+            rustls::ClientConnection::new(Arc::new(utils::make_configs().1), domain).unwrap();
+        session.write_tls(&mut hello).unwrap();
+        for byte in hello {
+            if cstream.write_all(&[byte]).await.is_err() {
+                break;
+            }
+            time::sleep(Duration::from_millis(5)).await;
+        }
+        cstream
+    });
+    let err = acceptor.accept(sstream).await.unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::TimedOut);
+    trickle.abort();
+
+    // A lazily configured handshake picks the limit up from the acceptor, here with a
+    // client that sends its ClientHello and then goes quiet.
+    let (mut cstream, sstream) = tokio::io::duplex(1200);
+    let mut hello = Vec::new();
+    let mut session = rustls::ClientConnection::new(
+        Arc::new(utils::make_configs().1),
+        pki_types::ServerName::try_from("foobar.com")
+            .unwrap()
+            .to_owned(),
+    )
+    .unwrap();
+    session.write_tls(&mut hello).unwrap();
+    cstream.write_all(&hello).await?;
+    let start = LazyConfigAcceptor::new(rustls::server::Acceptor::default(), sstream).await?;
+    let err = start
+        .into_stream_with_acceptor(&acceptor, |_| ())
+        .await
+        .unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::TimedOut);
+
+    // The limit applies to a lazy handshake too, from its first poll.
+    let (cstream, _sstream) = tokio::io::duplex(1200);
+    let mut client = connector.connect_lazy(
+        pki_types::ServerName::try_from("foobar.com")
+            .unwrap()
+            .to_owned(),
+        cstream,
+    )?;
+    let err = client.write_all(b"hello").await.unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::TimedOut);
+
+    // Once the handshake completes, the limit no longer matters.
+    let (cstream, sstream) = tokio::io::duplex(1200);
+    let (mut client, mut server) = handshake(&connector, &acceptor, cstream, sstream).await?;
+    time::sleep(limit * 2).await;
+    client.write_all(b"hello").await?;
+    client.flush().await?;
+    let mut buf = [0; 5];
+    server.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"hello");
+    Ok(())
+}
+
+#[tokio::test]
+async fn handshake_interrupted() -> io::Result<()> {
+    fn phase(err: &io::Error) -> Option<HandshakePhase> {
+        err.get_ref()
+            .and_then(|err| err.downcast_ref::<HandshakeInterrupted>())
+            .map(|interrupted| interrupted.phase())
+    }
+
+    let (sconfig, cconfig) = utils::make_configs();
+    let sconfig = Arc::new(sconfig);
+    let connector = TlsConnector::from(Arc::new(cconfig));
+    let acceptor = TlsAcceptor::from(sconfig.clone());
+    let domain = pki_types::ServerName::try_from("foobar.com")
+        .unwrap()
+        .to_owned();
+
+    // The client goes away without sending anything.
+    let (cstream, sstream) = tokio::io::duplex(1200);
+    drop(cstream);
+    let err = acceptor.accept(sstream).await.unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+    assert_eq!(phase(&err), Some(HandshakePhase::Start));
+
+    // The server goes away after reading the ClientHello.
+    let (cstream, mut sstream) = tokio::io::duplex(1200);
+    let server = async move {
+        let mut buf = [0; 5];
+        sstream.read_exact(&mut buf).await?;
+        Ok::<_, io::Error>(())
+    };
+    let (err, _) = tokio::join!(connector.connect(domain.clone(), cstream), server);
+    let err = err.unwrap_err();
+    assert_eq!(phase(&err), Some(HandshakePhase::ClientHello));
+
+    // The server goes away after sending its ServerHello, and nothing more.
+    let (cstream, mut sstream) = tokio::io::duplex(4096);
+    let server = async move {
+        let mut session = rustls::ServerConnection::new(sconfig).unwrap();
+        let mut buf = [0; 4096];
+        while session.is_handshaking() && !session.wants_write() {
+            let n = sstream.read(&mut buf).await?;
+            session.read_tls(&mut &buf[..n])?;
+            session.process_new_packets().unwrap();
+        }
+
+        let mut flight = Vec::new();
+        session.write_tls(&mut flight)?;
+        let len = 5 + usize::from(u16::from_be_bytes([flight[3], flight[4]]));
+        sstream.write_all(&flight[..len]).await
+    };
+    let (err, server) = tokio::join!(connector.connect(domain, cstream), server);
+    server?;
+    let err = err.unwrap_err();
+    assert_eq!(phase(&err), Some(HandshakePhase::ServerHello));
+    Ok(())
+}
+
+#[tokio::test]
+async fn data_available() -> io::Result<()> {
+    let (mut client, mut server) = connected_pair(1200).await?;
+
+    // A key update carries no application data.
+    server
+        .get_mut()
+        .1
+        .refresh_traffic_keys()
+        .map_err(|err| io::Error::new(ErrorKind::Other, err))?;
+    server.flush().await?;
+    let idle = time::timeout(Duration::from_millis(20), client.data_available()).await;
+    assert!(idle.is_err());
+
+    server.write_all(b"hello").await?;
+    server.flush().await?;
+    client.data_available().await?;
+    let mut buf = [0; 5];
+    client.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"hello");
+
+    server.shutdown().await?;
+    client.data_available().await?;
+    assert_eq!(client.read(&mut buf).await?, 0);
+    Ok(())
+}
+
+#[tokio::test]
+async fn connection_id() -> io::Result<()> {
+    let (sconfig, cconfig) = utils::make_configs();
+    let connector = TlsConnector::from(Arc::new(cconfig));
+    let acceptor = TlsAcceptor::from(Arc::new(sconfig));
+    let domain = pki_types::ServerName::try_from("foobar.com")
+        .unwrap()
+        .to_owned();
+
+    let (cstream, sstream) = tokio::io::duplex(1200);
+    let client = connector.connect_lazy(domain, cstream)?;
+    let id = client.connection_id();
+    let (mut client, server) = tokio::try_join!(
+        async {
+            let mut client = client;
+            client.flush().await?;
+            Ok(client)
+        },
+        acceptor.accept(sstream)
+    )?;
+    assert_eq!(client.connection_id(), id);
+    assert_ne!(server.connection_id(), id);
+
+    let (cstream, sstream) = tokio::io::duplex(1200);
+    let (other, _) = handshake(&connector, &acceptor, cstream, sstream).await?;
+    assert_ne!(other.connection_id(), id);
+    client.shutdown().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn on_established() -> io::Result<()> {
+    let (sconfig, cconfig) = utils::make_configs();
+    let connector = TlsConnector::from(Arc::new(cconfig));
+    let acceptor = TlsAcceptor::from(Arc::new(sconfig));
+    let domain = pki_types::ServerName::try_from("foobar.com")
+        .unwrap()
+        .to_owned();
+
+    let (ctx, mut crx) = tokio::sync::mpsc::unbounded_channel();
+    let (stx, mut srx) = tokio::sync::mpsc::unbounded_channel();
+    let (cstream, sstream) = tokio::io::duplex(1200);
+    let (mut client, _server) = tokio::try_join!(
+        connector
+            .connect(domain.clone(), cstream)
+            .on_established(move |summary| ctx.send(summary).unwrap()),
+        acceptor
+            .accept(sstream)
+            .on_established(move |summary| stx.send(summary).unwrap())
+    )?;
+    assert_eq!(
+        crx.try_recv().unwrap().protocol_version,
+        client.handshake_summary().protocol_version
+    );
+    assert!(crx.try_recv().is_err());
+    assert_eq!(srx.try_recv().unwrap().peer_certificates, 0);
+
+    // Registered after the handshake, the callback runs right away.
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    client.on_established(move |summary| tx.send(summary).unwrap());
+    assert!(rx.try_recv().is_ok());
+
+    // A lazy stream runs it when the handshake completes in the background.
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    let (cstream, sstream) = tokio::io::duplex(1200);
+    let mut client = connector.connect_lazy(domain, cstream)?;
+    client.on_established(move |summary| tx.send(summary).unwrap());
+    assert!(rx.try_recv().is_err());
+    let (_, _server) = tokio::try_join!(client.flush(), acceptor.accept(sstream))?;
+    assert_eq!(
+        rx.try_recv().unwrap().peer_certificates,
+        client.handshake_summary().peer_certificates
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn into_inner_after_close_notify() -> io::Result<()> {
+    let (sconfig, cconfig) = utils::make_configs();
+
+    let (cstream, sstream) = tokio::io::duplex(1200);
+    let domain = pki_types::ServerName::try_from("foobar.com")
+        .unwrap()
+        .to_owned();
+    let connector = TlsConnector::from(Arc::new(cconfig));
+    let acceptor = TlsAcceptor::from(Arc::new(sconfig));
+
+    let (client, server) = tokio::try_join!(
+        connector.connect(domain, KeepTrailingData::new(cstream)),
+        acceptor.accept(sstream)
+    )?;
+
+    // The close_notify and the plaintext after it arrive in a single read.
+    let (mut sstream, mut session) = server.into_inner();
+    io::Write::write_all(&mut session.writer(), b"hello")?;
+    session.send_close_notify();
+    let mut wire = Vec::new();
+    while session.wants_write() {
+        session.write_tls(&mut wire)?;
+    }
+    wire.extend_from_slice(b"plain");
+    sstream.write_all(&wire).await?;
+
+    let mut client = match client.into_inner_after_close_notify() {
+        Ok(_) => panic!("close_notify not received yet"),
+        Err(client) => client,
+    };
+    let mut buf = Vec::new();
+    client.read_to_end(&mut buf).await?;
+    assert_eq!(buf, b"hello");
+
+    let (mut cstream, trailing) = match client.into_inner_after_close_notify() {
+        Ok(parts) => parts,
+        Err(_) => panic!("close_notify received"),
+    };
+    assert_eq!(trailing, b"plain");
+
+    sstream.write_all(b" text").await?;
+    let mut buf = [0; 5];
+    cstream.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b" text");
+    Ok(())
+}
+
+#[tokio::test]
+async fn downgrade() -> io::Result<()> {
+    let (sconfig, cconfig) = utils::make_configs();
+    let connector = TlsConnector::from(Arc::new(cconfig));
+    let acceptor = TlsAcceptor::from(Arc::new(sconfig));
+    let domain = pki_types::ServerName::try_from("foobar.com")
+        .unwrap()
+        .to_owned();
+
+    // Both sides downgrade, then carry on in plaintext.
+    let (cstream, sstream) = tokio::io::duplex(1200);
+    let (client, server) = tokio::try_join!(
+        connector.connect(domain.clone(), KeepTrailingData::new(cstream)),
+        acceptor.accept(KeepTrailingData::new(sstream))
+    )?;
+    let ((mut cstream, ctrailing), (mut sstream, strailing)) = tokio::try_join!(
+        client.downgrade().map_err(|(err, _)| err),
+        server.downgrade().map_err(|(err, _)| err)
+    )?;
+    assert!(ctrailing.is_empty() && strailing.is_empty());
+
+    cstream.write_all(b"plain").await?;
+    let mut buf = [0; 5];
+    sstream.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"plain");
+
+    // Plaintext sent right behind the client's close_notify is kept.
+    let (cstream, sstream) = tokio::io::duplex(1200);
+    let (client, server) = tokio::try_join!(
+        connector.connect(domain, cstream),
+        acceptor.accept(KeepTrailingData::new(sstream))
+    )?;
+    let (mut cstream, mut session) = client.into_inner();
+    session.send_close_notify();
+    let mut wire = Vec::new();
+    while session.wants_write() {
+        session.write_tls(&mut wire)?;
+    }
+    wire.extend_from_slice(b"plain");
+    cstream.write_all(&wire).await?;
+
+    let (_, trailing) = server.downgrade().await.map_err(|(err, _)| err)?;
+    assert_eq!(trailing, b"plain");
+    Ok(())
+}
+
+#[tokio::test]
+async fn downgrade_with_unread_plaintext() -> io::Result<()> {
+    let (sconfig, cconfig) = utils::make_configs();
+    let connector = TlsConnector::from(Arc::new(cconfig));
+    let acceptor = TlsAcceptor::from(Arc::new(sconfig));
+
+    let (cstream, sstream) = tokio::io::duplex(1200);
+    let (mut client, server) = handshake(
+        &connector,
+        &acceptor,
+        cstream,
+        KeepTrailingData::new(sstream),
+    )
+    .await?;
+    client.write_all(b"hello").await?;
+    client.flush().await?;
+
+    // The server has not read the client's data, so the downgrade fails, but hands the
+    // stream back with the data still readable.
+    let (err, mut server) = server.downgrade().await.unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidData);
+    let mut buf = [0; 5];
+    server.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"hello");
+
+    // Once it has, the downgrade can be tried again.
+    client.shutdown().await?;
+    let (_, trailing) = server.downgrade().await.map_err(|(err, _)| err)?;
+    assert!(trailing.is_empty());
+    Ok(())
+}
+
+#[tokio::test]
+async fn instrumented_counts_bytes() -> io::Result<()> {
+    let (client, server) = connected_pair(1200).await?;
+    let mut client = Instrumented::new(client);
+    let mut server = Instrumented::new(server);
+
+    client.write_all(b"hello").await?;
+    client.flush().await?;
+    let mut buf = [0; 5];
+    server.read_exact(&mut buf).await?;
+
+    assert_eq!(client.bytes_written(), 5);
+    assert_eq!(client.bytes_read(), 0);
+    assert_eq!(server.bytes_read(), 5);
+    // TLS accessors are reachable through `Deref`.
+    assert!(!client.get_ref().1.is_handshaking());
+
+    let stats = client.reset_stats();
+    assert_eq!((stats.bytes_read, stats.bytes_written), (0, 5));
+    assert_eq!(client.bytes_written(), 0);
+    assert_eq!(client.reset_stats(), IoStats::default());
+    Ok(())
+}
+
+#[tokio::test]
+async fn buffered_bytes_within_buffer_limit() -> io::Result<()> {
+    let (mut client, _server) = connected_pair(1200).await?;
+
+    // The server never reads, so most of this stays buffered in the session.
+    client.get_mut().1.set_buffer_limit(Some(8192));
+    let written = client.write(&[0; 4096]).await?;
+    assert_eq!(written, 4096);
+    let (_, ciphertext) = client.buffered_bytes()?;
+    assert!(ciphertext > 2048, "{}", ciphertext);
+
+    let writable = 8192 - ciphertext;
+    let written = client.write(&vec![0; writable]).await?;
+    assert_eq!(written, writable);
+    Ok(())
+}
+
+#[tokio::test]
+async fn connect_with_connection() -> io::Result<()> {
+    let (sconfig, cconfig) = utils::make_configs();
+
+    let (cstream, sstream) = tokio::io::duplex(1200);
+    let domain = pki_types::ServerName::try_from("foobar.com")
+        .unwrap()
+        .to_owned();
+    let session = rustls::ClientConnection::new(Arc::new(cconfig), domain)
+        .map_err(|err| io::Error::new(ErrorKind::Other, err))?;
+
+    let (_, other) = utils::make_configs();
+    let connector = TlsConnector::from(Arc::new(other));
+    let acceptor = TlsAcceptor::from(Arc::new(sconfig));
+
+    let (mut client, mut server) = tokio::try_join!(
+        connector.connect_with_connection(session, cstream),
+        acceptor.accept(sstream)
+    )?;
+
+    client.write_all(b"hello").await?;
+    client.flush().await?;
+    let mut buf = [0; 5];
+    server.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"hello");
+    Ok(())
+}
+
+#[tokio::test]
+async fn connect_abort() -> io::Result<()> {
+    let (_, cconfig) = utils::make_configs();
+
+    let (cstream, mut sstream) = tokio::io::duplex(1200);
+    let domain = pki_types::ServerName::try_from("foobar.com")
+        .unwrap()
+        .to_owned();
+    let connector = TlsConnector::from(Arc::new(cconfig));
+
+    // The peer never answers, so the handshake stalls after the ClientHello.
+    let mut connect = connector.connect(domain, cstream);
+    assert!(time::timeout(Duration::from_millis(10), &mut connect)
+        .await
+        .is_err());
+    let mut cstream = connect.abort().unwrap();
+
+    let mut hello = [0; 5];
+    sstream.read_exact(&mut hello).await?;
+    assert_eq!(hello[0], 0x16);
+
+    // The IO is usable for a plaintext fallback.
+    cstream.write_all(b"plain").await?;
+    let len = u16::from_be_bytes([hello[3], hello[4]]) as usize;
+    let mut buf = vec![0; len + 5];
+    sstream.read_exact(&mut buf).await?;
+    assert_eq!(&buf[len..], b"plain");
+    Ok(())
+}
+
+#[tokio::test]
+async fn accept_abort() -> io::Result<()> {
+    let (sconfig, _) = utils::make_configs();
+
+    let (mut cstream, sstream) = tokio::io::duplex(1200);
+    let acceptor = TlsAcceptor::from(Arc::new(sconfig));
+
+    // The client never sends a ClientHello, so the handshake stalls.
+    let mut accept = acceptor.accept(sstream);
+    assert!(time::timeout(Duration::from_millis(10), &mut accept)
+        .await
+        .is_err());
+    let mut sstream = accept.abort().unwrap();
+
+    // The IO is usable for a plaintext fallback, with nothing sent ahead of it.
+    sstream.write_all(b"plain").await?;
+    let mut buf = [0; 5];
+    cstream.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"plain");
+    Ok(())
+}
+
+#[tokio::test]
+async fn copy_with_tee() -> io::Result<()> {
+    let (mut client, mut server) = connected_pair(1200).await?;
+
+    let data = (0..200_000).map(|i| i as u8).collect::<Vec<_>>();
+    // A backend far slower than the in-memory audit sink.
+    let (mut backend, mut backend_peer) = tokio::io::duplex(64);
+    let mut audit = Vec::new();
+
+    let ((), copied, forwarded) = tokio::try_join!(
+        async {
+            client.write_all(&data).await?;
+            client.shutdown().await
+        },
+        async {
+            let copied = server.copy_with_tee(&mut backend, &mut audit).await?;
+            backend.shutdown().await?;
+            Ok(copied)
+        },
+        async {
+            let mut buf = Vec::new();
+            backend_peer.read_to_end(&mut buf).await?;
+            Ok(buf)
+        }
+    )?;
+
+    assert_eq!(copied, data.len() as u64);
+    assert_eq!(forwarded, data);
+    assert_eq!(audit, data);
+    Ok(())
+}
+
+type Records = Arc<std::sync::Mutex<Vec<RecordMeta>>>;
+
+/// Wraps `io` to keep a list of the records that pass through it.
+fn record_log<IO>(io: IO) -> (ObserveRecords<IO>, Records) {
+    let records = Records::default();
+    let observed = records.clone();
+    let io = ObserveRecords::new(
+        io,
+        Arc::new(move |meta| observed.lock().unwrap().push(meta)),
+    );
+    (io, records)
+}
+
+/// The number of records written so far.
+fn writes(records: &Records) -> usize {
+    let records = records.lock().unwrap();
+    records
+        .iter()
+        .filter(|meta| meta.direction == Direction::Write)
+        .count()
+}
+
+#[tokio::test]
+async fn observe_records() -> io::Result<()> {
+    let (sconfig, cconfig) = utils::make_configs();
+
+    let (cstream, sstream) = tokio::io::duplex(1200);
+    let connector = TlsConnector::from(Arc::new(cconfig));
+    let acceptor = TlsAcceptor::from(Arc::new(sconfig));
+
+    let (cstream, records) = record_log(cstream);
+
+    let (mut client, mut server) = handshake(&connector, &acceptor, cstream, sstream).await?;
+
+    {
+        let records = records.lock().unwrap();
+        assert_eq!(records[0].content_type, rustls::ContentType::Handshake);
+        assert_eq!(records[0].direction, Direction::Write);
+        assert!(records.iter().any(|meta| meta.direction == Direction::Read));
+    }
+
+    records.lock().unwrap().clear();
+    client.write_all(b"hello").await?;
+    client.flush().await?;
+    let mut buf = [0; 5];
+    server.read_exact(&mut buf).await?;
+
+    // The plaintext, its inner content type and the AEAD tag.
+    let records: Vec<RecordMeta> = records.lock().unwrap().clone();
+    assert_eq!(records.len(), 1);
+    assert_eq!(
+        records[0].content_type,
+        rustls::ContentType::ApplicationData
+    );
+    assert_eq!(records[0].length, 5 + 1 + 16);
+    Ok(())
+}
+
+#[tokio::test]
+async fn batch() -> io::Result<()> {
+    use std::io::Write;
+
+    let (sconfig, cconfig) = utils::make_configs();
+
+    let (cstream, sstream) = tokio::io::duplex(1200);
+    let connector = TlsConnector::from(Arc::new(cconfig));
+    let acceptor = TlsAcceptor::from(Arc::new(sconfig));
+
+    let (cstream, records) = record_log(cstream);
+
+    let (mut client, mut server) = handshake(&connector, &acceptor, cstream, sstream).await?;
+    records.lock().unwrap().clear();
+
+    let n = client
+        .batch(|w| {
+            w.write_all(b"one")?;
+            w.write_all(b"two")?;
+            w.write_all(b"three")?;
+            Ok(11)
+        })
+        .await?;
+    assert_eq!(n, 11);
+    let mut buf = [0; 11];
+    server.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"onetwothree");
+    assert_eq!(writes(&records), 1);
+
+    // What was written before an error is still sent.
+    let err = client
+        .batch(|w| {
+            w.write_all(b"four")?;
+            Err::<(), _>(io::Error::new(ErrorKind::Other, "stop"))
+        })
+        .await
+        .unwrap_err();
+    assert_eq!(err.to_string(), "stop");
+    let mut buf = [0; 4];
+    server.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"four");
+    Ok(())
+}
+
+#[tokio::test]
+async fn key_update_handle() -> io::Result<()> {
+    let (sconfig, cconfig) = utils::make_configs();
+
+    let (cstream, sstream) = tokio::io::duplex(1200);
+    let connector = TlsConnector::from(Arc::new(cconfig));
+    let acceptor = TlsAcceptor::from(Arc::new(sconfig));
+
+    let (cstream, records) = record_log(cstream);
+
+    let (mut client, mut server) = handshake(&connector, &acceptor, cstream, sstream).await?;
+    let handle = client.key_update_handle();
+
+    let mut buf = [0; 5];
+    for (data, update) in [(b"hello", false), (b"world", true)] {
+        if update {
+            let handle = handle.clone();
+            tokio::spawn(async move { handle.request() }).await?;
+        }
+
+        records.lock().unwrap().clear();
+        client.write_all(data).await?;
+        client.flush().await?;
+        server.read_exact(&mut buf).await?;
+        assert_eq!(&buf, data);
+
+        // The key update goes out in a record of its own, ahead of the data.
+        assert_eq!(writes(&records), if update { 2 } else { 1 });
+    }
+
+    // The server answers with a key update of its own, which the client reads.
+    server.write_all(b"reply").await?;
+    server.flush().await?;
+    client.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"reply");
+    Ok(())
+}
+
+#[cfg(feature = "tls12")]
+#[tokio::test]
+async fn key_update_handle_tls12() -> io::Result<()> {
+    let (sconfig, cconfig) = utils::make_configs_with_versions(&[&rustls::version::TLS12]);
+
+    let connector = TlsConnector::from(Arc::new(cconfig));
+    let acceptor = TlsAcceptor::from(Arc::new(sconfig));
+
+    let (cstream, sstream) = tokio::io::duplex(1200);
+    let (mut client, _server) = handshake(&connector, &acceptor, cstream, sstream).await?;
+    client.key_update_handle().request();
+    let err = client.write_all(b"hello").await.unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::Unsupported);
+    Ok(())
+}
+
+#[tokio::test]
+async fn read_rate_limit() -> io::Result<()> {
+    let (mut client, mut server) = connected_pair(1200).await?;
+    server.set_read_rate_limit(std::num::NonZeroU64::new(10_000));
+
+    let start = time::Instant::now();
+    let (_, received) = tokio::try_join!(
+        async {
+            client.write_all(&[0; 15_000]).await?;
+            client.shutdown().await
+        },
+        async {
+            let mut received = 0;
+            let mut buf = [0; 1000];
+            loop {
+                match server.read(&mut buf).await? {
+                    0 => break io::Result::Ok(received),
+                    n => received += n,
+                }
+            }
+        }
+    )?;
+
+    // The first 10 KB are a burst; the rest takes about half a second.
+    assert_eq!(received, 15_000);
+    assert!(start.elapsed() >= Duration::from_millis(400));
+    Ok(())
+}
+
+#[tokio::test]
+async fn read_rate_limit_caps_buffer() -> io::Result<()> {
+    let (mut client, mut server) = connected_pair(1200).await?;
+    server.set_read_rate_limit(std::num::NonZeroU64::new(100));
+
+    client.write_all(&[0; 1000]).await?;
+    client.flush().await?;
+
+    // A buffer larger than the bucket is only filled as far as the tokens allow.
+    let mut buf = [0; 4096];
+    assert_eq!(server.read(&mut buf).await?, 100);
+    Ok(())
+}
+
+#[tokio::test]
+async fn peer_spki_sha256() -> io::Result<()> {
+    // openssl x509 -in tests/certs/chain.pem -pubkey -noout \
+    //     | openssl pkey -pubin -outform der | sha256sum
+    const EXPECTED: &str = "9f31a03985e3bd2c8beb4c004db06103071dd393b159a6b843b95bee1dc484fc";
+
+    let (client, _server) = connected_pair(1200).await?;
+
+    let hash = client.peer_spki_sha256().unwrap();
+    let hex = hash
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<String>();
+    assert_eq!(hex, EXPECTED);
+    Ok(())
+}
+
+#[tokio::test]
+async fn peer_cert_validity() -> io::Result<()> {
+    // openssl x509 -in tests/certs/chain.pem -noout -dates
+    // notBefore is a UTCTime, 1975-01-01; notAfter a GeneralizedTime, 4096-01-01.
+    const NOT_BEFORE: u64 = 157_766_400;
+    const NOT_AFTER: u64 = 67_090_118_400;
+
+    let (sconfig, cconfig) = utils::make_configs();
+
+    let (cstream, sstream) = tokio::io::duplex(1200);
+    let domain = pki_types::ServerName::try_from("foobar.com")
+        .unwrap()
+        .to_owned();
+    let connector = TlsConnector::from(Arc::new(cconfig));
+    let acceptor = TlsAcceptor::from(Arc::new(sconfig));
+
+    let client = connector.connect_lazy(domain, cstream)?;
+    assert!(client.peer_cert_validity().is_none());
+    let (client, _server) = tokio::try_join!(
+        async {
+            let mut client = client;
+            client.flush().await?;
+            Ok(client)
+        },
+        acceptor.accept(sstream)
+    )?;
+
+    let (not_before, not_after) = client.peer_cert_validity().unwrap();
+    let since_epoch = |time: SystemTime| time.duration_since(SystemTime::UNIX_EPOCH).unwrap();
+    assert_eq!(since_epoch(not_before), Duration::from_secs(NOT_BEFORE));
+    assert_eq!(since_epoch(not_after), Duration::from_secs(NOT_AFTER));
+    Ok(())
+}
+
+#[tokio::test]
+async fn drain_with_deadline() -> io::Result<()> {
+    let (sconfig, cconfig) = utils::make_configs();
+    let connector = TlsConnector::from(Arc::new(cconfig));
+    let acceptor = TlsAcceptor::from(Arc::new(sconfig));
+    let domain = pki_types::ServerName::try_from("foobar.com")
+        .unwrap()
+        .to_owned();
+
+    let mut clients = Vec::new();
+    let mut servers = Vec::new();
+    for _ in 0..2 {
+        let (cstream, sstream) = tokio::io::duplex(1200);
+        let (client, server) = tokio::try_join!(
+            connector.connect(domain.clone(), cstream),
+            acceptor.accept(sstream)
+        )?;
+        clients.push(client);
+        servers.push(server);
+    }
+
+    // Nobody reads from the second connection, so its close_notify can't be flushed.
+    let stalled = &mut clients[1];
+    while time::timeout(Duration::from_millis(10), stalled.write_all(&[0; 1024]))
+        .await
+        .is_ok()
+    {}
+
+    let deadline = time::sleep(Duration::from_millis(50));
+    let results = tokio_rustls::drain(clients, deadline).await;
+    assert_eq!(results.len(), 2);
+    assert!(results[0].is_ok());
+    assert_eq!(results[1].as_ref().unwrap_err().kind(), ErrorKind::TimedOut);
+
+    let mut buf = Vec::new();
+    servers[0].read_to_end(&mut buf).await?;
+    assert!(buf.is_empty());
+    Ok(())
+}
+
+#[tokio::test]
+async fn lazy_config_acceptor_reject() -> io::Result<()> {
+    let (_, cconfig) = utils::make_configs();
+
+    let (cstream, sstream) = tokio::io::duplex(1200);
+    let domain = pki_types::ServerName::try_from("unknown.com")
+        .unwrap()
+        .to_owned();
+    let client = tokio::spawn(async move {
+        let connector = TlsConnector::from(Arc::new(cconfig));
+        connector.connect(domain, cstream).await.map(|_| ())
+    });
+
+    let acceptor = LazyConfigAcceptor::new(rustls::server::Acceptor::default(), sstream);
+    let start = acceptor.await?;
+    assert_eq!(start.client_hello().server_name(), Some("unknown.com"));
+    let io = start
+        .reject(rustls::AlertDescription::UnrecognisedName)
+        .await?;
+    drop(io);
+
+    let err = client.await.unwrap().unwrap_err();
+    assert_eq!(err.to_string(), "received fatal alert: UnrecognisedName");
+    Ok(())
+}
+
 #[tokio::test]
 async fn acceptor_alert() {
     let (sconfig, _) = utils::make_configs();
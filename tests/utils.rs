@@ -1,12 +1,19 @@
 mod utils {
     use std::io::{BufReader, Cursor, IoSlice};
 
-    use rustls::{ClientConfig, RootCertStore, ServerConfig};
+    use rustls::{ClientConfig, RootCertStore, ServerConfig, SupportedProtocolVersion};
     use rustls_pemfile::{certs, private_key};
     use tokio::io::{self, AsyncWrite, AsyncWriteExt};
 
     #[allow(dead_code)]
     pub fn make_configs() -> (ServerConfig, ClientConfig) {
+        make_configs_with_versions(rustls::DEFAULT_VERSIONS)
+    }
+
+    #[allow(dead_code)]
+    pub fn make_configs_with_versions(
+        versions: &[&'static SupportedProtocolVersion],
+    ) -> (ServerConfig, ClientConfig) {
         // A test root certificate that is the trust anchor for the CHAIN.
         const ROOT: &str = include_str!("certs/root.pem");
         // A server certificate chain that includes both an end-entity server certificate
@@ -22,9 +29,9 @@ mod utils {
         let key = private_key(&mut BufReader::new(Cursor::new(EE_KEY)))
             .unwrap()
             .unwrap();
-        let sconfig = ServerConfig::builder()
+        let sconfig = ServerConfig::builder_with_protocol_versions(versions)
             .with_no_client_auth()
-            .with_single_cert(cert, key.into())
+            .with_single_cert(cert, key)
             .unwrap();
 
         let mut client_root_cert_store = RootCertStore::empty();
@@ -33,7 +40,7 @@ mod utils {
             client_root_cert_store.add(root.unwrap()).unwrap();
         }
 
-        let cconfig = ClientConfig::builder()
+        let cconfig = ClientConfig::builder_with_protocol_versions(versions)
             .with_root_certificates(client_root_cert_store)
             .with_no_client_auth();
 